@@ -1,4 +1,17 @@
 pub mod msg_types;
 pub mod state_types;
 pub mod response_types;
-pub mod instances_types;
\ No newline at end of file
+pub mod instances_types;
+pub mod config_types;
+pub mod moderation_types;
+pub mod directory_types;
+pub mod lobby_types;
+pub mod presence_types;
+pub mod connection_types;
+pub mod playback_types;
+pub mod buffering_types;
+pub mod ready_types;
+pub mod settings_types;
+pub mod metrics_types;
+pub mod watch_party_types;
+pub mod queue_mode_types;
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::msg_types::ServerMsg;
+use super::presence_types::PlaybackStatus;
+use super::state_types::{JvsState, UserRef};
+
+impl JvsState {
+    async fn send_buffering_pause(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let users: Vec<UserRef> = room.buffering.keys().filter_map(|user_id| room.user_ref(*user_id)).collect();
+        self.broadcast(room_id, &ServerMsg::BufferingPause { users }, None).await;
+    }
+
+    async fn start_buffering(&mut self, room_id: &str, user_id: Uuid) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        // Only a room that is actually playing (or already waiting) needs to wait for the user
+        let should_wait = room.settings.pause_on_buffering && !room.awaiting_ready && (room.playback.playing || room.buffering_pause);
+
+        if !should_wait || room.buffering.contains_key(&user_id) {
+            return;
+        }
+
+        room.buffering.insert(user_id, Instant::now());
+
+        if !room.buffering_pause {
+            room.buffering_pause = true;
+            self.set_room_playing(room_id, false).await;
+        }
+
+        self.send_buffering_pause(room_id).await;
+    }
+
+    // Resumes the room once nobody it waits for is still buffering
+    pub(super) async fn check_buffering(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        if !room.buffering_pause {
+            return;
+        }
+
+        room.buffering.retain(|user_id, _| room.users.contains_key(user_id));
+
+        if room.buffering.is_empty() {
+            room.buffering_pause = false;
+
+            self.broadcast(room_id, &ServerMsg::BufferingResumed, None).await;
+            self.set_room_playing(room_id, true).await;
+        } else {
+            self.send_buffering_pause(room_id).await;
+        }
+    }
+
+    // Drops the auto pause, e.g. when someone starts or pauses the video by hand
+    pub(super) fn clear_buffering(&mut self, room_id: &str) {
+        if let Some(room) = self.rooms.get_mut(room_id) {
+            room.buffering.clear();
+            room.buffering_pause = false;
+        }
+    }
+
+    // Stops waiting for users that have been buffering for longer than the configured maximum
+    pub(super) async fn expire_buffering(&mut self) {
+        let max_wait = Duration::from_secs(self.config.buffering_max_wait_secs);
+        let mut left_behind = Vec::new();
+
+        for (room_id, room) in self.rooms.iter_mut() {
+            let expired: Vec<Uuid> = room.buffering.iter()
+                .filter(|(_, since)| since.elapsed() >= max_wait)
+                .map(|(user_id, _)| *user_id)
+                .collect();
+
+            for user_id in expired {
+                room.buffering.remove(&user_id);
+                left_behind.push((room_id.clone(), user_id));
+            }
+        }
+
+        for (room_id, user_id) in left_behind.iter() {
+            let notice = ServerMsg::BufferingLeftBehind { waited_secs: max_wait.as_secs() };
+            self.send_to_user(*user_id, &notice).await;
+            self.check_buffering(room_id).await;
+        }
+    }
+}
+
+// Messages
+
+pub struct StateSetBufferingMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub buffering: bool,
+}
+
+impl Handler<StateSetBufferingMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StateSetBufferingMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(room) = self.rooms.get_mut(&message.room_id) else {
+            return;
+        };
+
+        let playing = room.playback.playing || room.buffering_pause;
+
+        let Some(user) = room.users.get_mut(&message.user_id) else {
+            return;
+        };
+
+        let status = match (message.buffering, playing) {
+            (true, _) => PlaybackStatus::Buffering,
+            (false, true) => PlaybackStatus::Playing,
+            (false, false) => PlaybackStatus::Paused,
+        };
+
+        if user.status != status {
+            user.status = status;
+            self.send_presence_changed(&message.room_id, message.user_id).await;
+        }
+
+        if message.buffering {
+            self.start_buffering(&message.room_id, message.user_id).await;
+        } else if self.rooms.get_mut(&message.room_id).is_some_and(|room| room.buffering.remove(&message.user_id).is_some()) {
+            self.check_buffering(&message.room_id).await;
+        }
+    }
+}
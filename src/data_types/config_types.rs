@@ -0,0 +1,164 @@
+use std::env;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipThreshold {
+    // Fraction of the users in the room, e.g. 0.5 for half of them
+    Fraction(f64),
+    Count(usize),
+}
+
+impl SkipThreshold {
+    pub fn required_votes(&self, users: usize) -> usize {
+        match *self {
+            SkipThreshold::Fraction(fraction) => ((users as f64 * fraction).ceil() as usize).max(1),
+            // Never require more votes than there are users to cast them
+            SkipThreshold::Count(count) => count.clamp(1, users.max(1)),
+        }
+    }
+
+    fn fraction(fraction: f64) -> Result<Self, String> {
+        if fraction > 0.0 && fraction <= 1.0 {
+            Ok(SkipThreshold::Fraction(fraction))
+        } else {
+            Err(format!("Skip threshold must be between 0 and 1, got {}", fraction))
+        }
+    }
+}
+
+impl FromStr for SkipThreshold {
+    type Err = String;
+
+    // Accepts "50%", "0.5" or an absolute count like "3"
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+
+        if let Some(percent) = value.strip_suffix('%') {
+            let percent: f64 = percent.trim().parse().map_err(|_| format!("Invalid percentage: {}", value))?;
+            return SkipThreshold::fraction(percent / 100.0);
+        }
+
+        if let Ok(count) = value.parse::<usize>() {
+            return Ok(SkipThreshold::Count(count));
+        }
+
+        let fraction: f64 = value.parse().map_err(|_| format!("Invalid skip threshold: {}", value))?;
+        SkipThreshold::fraction(fraction)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // Address of the HTTP api, e.g. the public room directory
+    pub http_address: String,
+    pub history_max_length: usize,
+    pub queue_max_length: usize,
+    // Default and upper bound of the per room limit
+    pub room_max_users: usize,
+    pub server_max_users: usize,
+    pub skip_threshold: SkipThreshold,
+    // Words that cannot appear in user names
+    pub blocked_name_words: Vec<String>,
+    // Default drift, in seconds, clients may have before they are resynced
+    pub drift_tolerance_secs: f64,
+    // Seeks and play state changes within this many milliseconds are merged into the last one, 0 disables it
+    pub control_coalesce_ms: u64,
+    // Seconds before another rewind of the same video is announced
+    pub rewind_announce_cooldown_secs: u64,
+    // Seconds to wait for everyone to load a new video before starting it anyway
+    pub ready_timeout_secs: u64,
+    // Longest a room waits for a buffering user before resuming without them
+    pub buffering_max_wait_secs: u64,
+    pub ping_interval_secs: u64,
+    // Connections that stay silent for this many pings are dropped
+    pub max_missed_pings: u32,
+    // Seconds an empty room is kept so its users can come back, 0 deletes it right away
+    pub empty_room_ttl_secs: u64,
+    // JSON file the scheduled watch parties are saved to, None disables persistence
+    pub persistence_file: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            http_address: "127.0.0.1:9002".to_string(),
+            history_max_length: 100,
+            queue_max_length: 100,
+            room_max_users: 50,
+            server_max_users: 1000,
+            skip_threshold: SkipThreshold::Fraction(0.5),
+            blocked_name_words: Vec::new(),
+            drift_tolerance_secs: 0.5,
+            control_coalesce_ms: 150,
+            rewind_announce_cooldown_secs: 300,
+            ready_timeout_secs: 10,
+            buffering_max_wait_secs: 15,
+            ping_interval_secs: 20,
+            max_missed_pings: 3,
+            empty_room_ttl_secs: 120,
+            persistence_file: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Reads the config from the environment (and .env), falling back to the defaults
+    pub fn from_env() -> Self {
+        let default = ServerConfig::default();
+
+        ServerConfig {
+            http_address: env_or("HTTP_ADDRESS", default.http_address),
+            history_max_length: env_or("HISTORY_MAX_LENGTH", default.history_max_length),
+            queue_max_length: env_or("QUEUE_MAX_LENGTH", default.queue_max_length),
+            room_max_users: env_or("ROOM_MAX_USERS", default.room_max_users),
+            server_max_users: env_or("SERVER_MAX_USERS", default.server_max_users),
+            skip_threshold: env_or("SKIP_VOTE_THRESHOLD", default.skip_threshold),
+            blocked_name_words: env::var("BLOCKED_NAME_WORDS")
+                .map(|words| words.split(',').map(|word| word.trim().to_string()).filter(|word| !word.is_empty()).collect())
+                .unwrap_or(default.blocked_name_words),
+            drift_tolerance_secs: env_or("DRIFT_TOLERANCE_SECS", default.drift_tolerance_secs),
+            control_coalesce_ms: env_or("CONTROL_COALESCE_MS", default.control_coalesce_ms),
+            rewind_announce_cooldown_secs: env_or("REWIND_ANNOUNCE_COOLDOWN_SECS", default.rewind_announce_cooldown_secs),
+            ready_timeout_secs: env_or("READY_TIMEOUT_SECS", default.ready_timeout_secs),
+            buffering_max_wait_secs: env_or("BUFFERING_MAX_WAIT_SECS", default.buffering_max_wait_secs),
+            ping_interval_secs: env_or("PING_INTERVAL_SECS", default.ping_interval_secs).max(1),
+            max_missed_pings: env_or("MAX_MISSED_PINGS", default.max_missed_pings).max(1),
+            empty_room_ttl_secs: env_or("EMPTY_ROOM_TTL_SECS", default.empty_room_ttl_secs),
+            persistence_file: env::var("PERSISTENCE_FILE").ok().filter(|path| !path.is_empty()).or(default.persistence_file),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_skip_thresholds() {
+        assert_eq!("50%".parse(), Ok(SkipThreshold::Fraction(0.5)));
+        assert_eq!(" 0.25 ".parse(), Ok(SkipThreshold::Fraction(0.25)));
+        assert_eq!("1.0".parse(), Ok(SkipThreshold::Fraction(1.0)));
+        assert_eq!("3".parse(), Ok(SkipThreshold::Count(3)));
+    }
+
+    #[test]
+    fn rejects_invalid_skip_thresholds() {
+        assert!("".parse::<SkipThreshold>().is_err());
+        assert!("half".parse::<SkipThreshold>().is_err());
+        assert!("0%".parse::<SkipThreshold>().is_err());
+        assert!("150%".parse::<SkipThreshold>().is_err());
+        assert!("-0.5".parse::<SkipThreshold>().is_err());
+    }
+
+    #[test]
+    fn computes_required_votes() {
+        assert_eq!(SkipThreshold::Fraction(0.5).required_votes(5), 3);
+        assert_eq!(SkipThreshold::Fraction(0.5).required_votes(0), 1);
+        assert_eq!(SkipThreshold::Count(3).required_votes(2), 2);
+        assert_eq!(SkipThreshold::Count(0).required_votes(4), 1);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::Instant;
+use futures_util::{stream::SplitSink, SinkExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::utils::now_millis;
+
+use super::msg_types::ServerMsg;
+use super::state_types::JvsState;
+
+// Pings older than this many are assumed lost
+const MAX_PENDING_PINGS: usize = 5;
+// Weight of a new sample in the smoothed round trip time
+const RTT_SMOOTHING: f64 = 0.2;
+// Smaller changes are not worth a presence update
+const RTT_CHANGE_THRESHOLD_MS: f64 = 10.0;
+
+#[derive(Debug)]
+pub struct Connection {
+    pub ws: SplitSink<WebSocketStream<TcpStream>, Message>,
+    pub ip: IpAddr,
+    // Dropping it stops the task handling the connection
+    pub closed: oneshot::Sender<()>,
+    // Smoothed round trip time in milliseconds
+    pub rtt_ms: Option<f64>,
+    // Whether the client gets the echo of its own playback actions
+    pub echo_own_actions: bool,
+    // Whether the client wants readable notices such as "Ana paused at 12:03"
+    pub system_notices: bool,
+    // Last round trip time sent in the presence updates
+    reported_rtt_ms: Option<f64>,
+    next_ping_nonce: u64,
+    pending_pings: VecDeque<(u64, Instant)>,
+    // Pings in a row without any traffic from the client
+    missed_pings: u32,
+}
+
+impl Connection {
+    pub fn new(ws: SplitSink<WebSocketStream<TcpStream>, Message>, ip: IpAddr, closed: oneshot::Sender<()>) -> Self {
+        Connection {
+            ws,
+            ip,
+            closed,
+            rtt_ms: None,
+            echo_own_actions: true,
+            system_notices: false,
+            reported_rtt_ms: None,
+            next_ping_nonce: 0,
+            pending_pings: VecDeque::new(),
+            missed_pings: 0,
+        }
+    }
+
+    // Estimated time in seconds for a message to reach the client
+    pub fn one_way_delay(&self) -> f64 {
+        self.rtt_ms.unwrap_or_default() / 2000.0
+    }
+}
+
+impl JvsState {
+    pub(super) fn room_of(&self, user_id: Uuid) -> Option<String> {
+        self.rooms.iter().find(|(_, room)| room.users.contains_key(&user_id)).map(|(room_id, _)| room_id.clone())
+    }
+
+    pub(super) fn one_way_delay(&self, user_id: Uuid) -> f64 {
+        self.connections.get(&user_id).map(|connection| connection.one_way_delay()).unwrap_or_default()
+    }
+
+    // Broadcasts an action, skipping its sender if it opted out of echoes
+    pub(super) async fn broadcast_action(&mut self, room_id: &str, message: &ServerMsg, sender: Uuid) {
+        let echo = self.connections.get(&sender).is_none_or(|connection| connection.echo_own_actions);
+        self.broadcast(room_id, message, (!echo).then_some(sender)).await;
+    }
+
+    // Sends a notice to the users of the room that asked for them
+    pub(super) async fn send_system_notice(&mut self, room_id: &str, message: String) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let recipients: Vec<Uuid> = room.users.keys()
+            .filter(|user_id| self.connections.get(user_id).is_some_and(|connection| connection.system_notices))
+            .copied()
+            .collect();
+
+        let notice = ServerMsg::SystemNotice { message, sent_at: now_millis() };
+
+        for user_id in recipients {
+            self.send_to_user(user_id, &notice).await;
+        }
+    }
+}
+
+// Messages
+
+pub struct StatePingMessage {
+    pub user_id: Uuid,
+    // Whether the client sent anything since the previous ping
+    pub active: bool,
+}
+
+pub struct StatePongMessage {
+    pub user_id: Uuid,
+    pub nonce: u64,
+}
+
+// Options left as None keep their current value
+pub struct StateSetClientOptionsMessage {
+    pub user_id: Uuid,
+    pub echo_own_actions: Option<bool>,
+    pub system_notices: Option<bool>,
+}
+
+impl Handler<StatePingMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StatePingMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let max_missed_pings = self.config.max_missed_pings;
+
+        let Some(connection) = self.connections.get_mut(&message.user_id) else {
+            return;
+        };
+
+        if message.active {
+            connection.missed_pings = 0;
+        } else {
+            connection.missed_pings += 1;
+        }
+
+        if connection.missed_pings >= max_missed_pings {
+            println!("Dropping unresponsive connection: {}", connection.ip);
+            self.remove_user(message.user_id).await;
+            return;
+        }
+
+        let nonce = connection.next_ping_nonce;
+        connection.next_ping_nonce += 1;
+
+        connection.pending_pings.push_back((nonce, Instant::now()));
+
+        if connection.pending_pings.len() > MAX_PENDING_PINGS {
+            connection.pending_pings.pop_front();
+        }
+
+        // Protocol pings are answered by the websocket library even if the page is frozen
+        let _ = connection.ws.send(Message::Ping(nonce.to_be_bytes().to_vec())).await;
+
+        self.send_to_user(message.user_id, &ServerMsg::Ping { nonce, sent_at: now_millis() }).await;
+    }
+}
+
+impl Handler<StatePongMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StatePongMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(connection) = self.connections.get_mut(&message.user_id) else {
+            return;
+        };
+
+        let Some(index) = connection.pending_pings.iter().position(|(nonce, _)| *nonce == message.nonce) else {
+            return;
+        };
+
+        // Pings sent before the answered one will not be answered anymore
+        let (_, sent_at) = connection.pending_pings.drain(..=index).next_back().unwrap();
+        let sample = sent_at.elapsed().as_secs_f64() * 1000.0;
+
+        let rtt = match connection.rtt_ms {
+            Some(rtt) => rtt + RTT_SMOOTHING * (sample - rtt),
+            None => sample,
+        };
+
+        connection.rtt_ms = Some(rtt);
+
+        if connection.reported_rtt_ms.is_none_or(|reported| (reported - rtt).abs() >= RTT_CHANGE_THRESHOLD_MS) {
+            connection.reported_rtt_ms = Some(rtt);
+
+            if let Some(room_id) = self.room_of(message.user_id) {
+                self.send_presence_changed(&room_id, message.user_id).await;
+            }
+        }
+    }
+}
+
+impl Handler<StateSetClientOptionsMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StateSetClientOptionsMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(connection) = self.connections.get_mut(&message.user_id) else {
+            return;
+        };
+
+        if let Some(echo_own_actions) = message.echo_own_actions {
+            connection.echo_own_actions = echo_own_actions;
+        }
+
+        if let Some(system_notices) = message.system_notices {
+            connection.system_notices = system_notices;
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use serde::Serialize;
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::settings_types::RoomSettingsUpdate;
+use super::state_types::JvsState;
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct RoomDirectoryEntry {
+    pub name: String,
+    pub user_count: usize,
+    pub video_title: Option<String>,
+    pub thumbnail: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct RoomDirectoryPage {
+    pub rooms: Vec<RoomDirectoryEntry>,
+    // Number of rooms matching the query, across all pages
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize
+}
+
+// Messages
+
+pub struct StateListRoomsMessage {
+    // Case insensitive search over the room names and the current video titles
+    pub query: Option<String>,
+    // Starts at 1
+    pub page: usize,
+    pub page_size: usize
+}
+
+pub struct StateSetRoomPublicMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub public: bool
+}
+
+impl Handler<StateListRoomsMessage> for JvsState {
+    type Return = RoomDirectoryPage;
+
+    async fn handle(
+        &mut self,
+        message: StateListRoomsMessage,
+        _ctx: &mut Context<Self>,
+    ) -> RoomDirectoryPage {
+        let query = message.query.map(|query| query.trim().to_lowercase()).filter(|query| !query.is_empty());
+        let page = message.page.max(1);
+        let page_size = message.page_size.clamp(1, MAX_PAGE_SIZE);
+
+        let mut rooms: Vec<RoomDirectoryEntry> = self.rooms.iter()
+            .filter(|(_, room)| room.settings.public && room.emptied_at.is_none())
+            .map(|(name, room)| {
+                let metadata = room.now_playing.as_ref().map(|entry| &entry.metadata);
+
+                RoomDirectoryEntry {
+                    name: name.clone(),
+                    user_count: room.users.len(),
+                    video_title: metadata.map(|metadata| metadata.title.clone()),
+                    thumbnail: metadata.and_then(|metadata| metadata.thumbnails.preferred()).map(|thumbnail| thumbnail.url.clone())
+                }
+            })
+            .filter(|entry| match &query {
+                Some(query) => entry.name.to_lowercase().contains(query)
+                    || entry.video_title.as_ref().is_some_and(|title| title.to_lowercase().contains(query)),
+                None => true,
+            })
+            .collect();
+
+        // Busiest rooms first
+        rooms.sort_by(|a, b| b.user_count.cmp(&a.user_count).then_with(|| a.name.cmp(&b.name)));
+
+        let total = rooms.len();
+        let rooms = rooms.into_iter().skip((page - 1).saturating_mul(page_size)).take(page_size).collect();
+
+        RoomDirectoryPage { rooms, total, page, page_size }
+    }
+}
+
+impl Handler<StateSetRoomPublicMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateSetRoomPublicMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let update = RoomSettingsUpdate { public: Some(message.public), ..Default::default() };
+
+        self.update_room_settings(&message.room_id, message.user_id, update).await
+    }
+}
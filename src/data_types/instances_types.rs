@@ -1,23 +1,10 @@
 use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
 use xtra::prelude::*;
 use dotenv::dotenv;
 use std::env;
 
 use super::response_types::YoutubeDataResponse;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct InstanceMonitor {
-    pub uptime: f64,
-    pub down: bool,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Instance {
-    pub uri: String,
-    pub monitor: Option<InstanceMonitor>,
-}
-
 // Actor
 #[derive(Default, xtra::Actor)]
 pub struct InstancesManager;
@@ -40,7 +27,7 @@ impl Handler<InstancesFetchVideoMessage> for InstancesManager {
         let api_key = env::var("YOUTUBE_API_KEY").expect("You need to write the YOUTUBE_API_KEY in .env");
 
         let response = reqwest::get(format!(
-            "https://www.googleapis.com/youtube/v3/videos?part=contentDetails,snippet&id={}&key={}",
+            "https://www.googleapis.com/youtube/v3/videos?part=contentDetails,snippet,status&id={}&key={}",
             message.video_id, api_key
         )).await;
 
@@ -60,7 +47,7 @@ impl Handler<InstancesFetchVideoMessage> for InstancesManager {
             Err(_) => return video_info,
         }
 
-        return video_info;
+        video_info
     }
 }
 
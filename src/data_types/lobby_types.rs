@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::moderation_types::Role;
+use super::msg_types::ServerMsg;
+use super::settings_types::RoomSettingsUpdate;
+use super::state_types::JvsState;
+
+#[derive(Debug, Clone)]
+pub struct LobbyEntry {
+    pub user_id: Uuid,
+    pub resume_token: Option<String>,
+    // Knocking users wait for a moderator before they get a place in line
+    pub approved: bool,
+}
+
+impl JvsState {
+    pub(super) fn total_users(&self) -> usize {
+        self.rooms.values().map(|room| room.users.len()).sum()
+    }
+
+    pub(super) fn room_max_users(&self, room_id: &str) -> usize {
+        self.rooms.get(room_id)
+            .map(|room| room.settings.max_users)
+            .unwrap_or(self.config.room_max_users)
+    }
+
+    pub(super) fn has_capacity(&self, room_id: &str) -> bool {
+        let Some(room) = self.rooms.get(room_id) else {
+            return false;
+        };
+
+        room.users.len() < self.room_max_users(room_id) && self.total_users() < self.config.server_max_users
+    }
+
+    pub(super) async fn send_lobby_positions(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let mut position = 0;
+        let mut updates = Vec::new();
+
+        for entry in room.lobby.iter() {
+            let update = if entry.approved {
+                position += 1;
+                ServerMsg::LobbyPosition { position: Some(position), awaiting_approval: false }
+            } else {
+                ServerMsg::LobbyPosition { position: None, awaiting_approval: true }
+            };
+
+            updates.push((entry.user_id, update));
+        }
+
+        for (user_id, update) in updates {
+            self.send_to_user(user_id, &update).await;
+        }
+    }
+
+    // Admits waiting users, in order, while the rooms and the server have free slots
+    pub(super) async fn admit_from_lobbies(&mut self) {
+        let room_ids: Vec<String> = self.rooms.iter()
+            .filter(|(_, room)| !room.lobby.is_empty())
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+
+        for room_id in room_ids {
+            let mut admitted = false;
+
+            while self.has_capacity(&room_id) {
+                let room = self.rooms.get_mut(&room_id).unwrap();
+                // Nobody is left to answer the knocks of an empty room
+                let everyone_approved = room.users.is_empty();
+
+                let Some(index) = room.lobby.iter().position(|entry| entry.approved || everyone_approved) else {
+                    break;
+                };
+
+                let entry = room.lobby.remove(index).unwrap();
+                self.admit_user(&room_id, entry.user_id, entry.resume_token).await;
+                admitted = true;
+            }
+
+            if admitted {
+                self.send_lobby_positions(&room_id).await;
+            }
+        }
+    }
+
+    // Removes the user from every lobby. Returns whether the user was waiting in one
+    pub(super) async fn leave_lobbies(&mut self, user_id: Uuid) -> bool {
+        let room_ids: Vec<String> = self.rooms.iter()
+            .filter(|(_, room)| room.lobby.iter().any(|entry| entry.user_id == user_id))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+
+        for room_id in room_ids.iter() {
+            let room = self.rooms.get_mut(room_id).unwrap();
+            room.lobby.retain(|entry| entry.user_id != user_id);
+
+            if room.users.is_empty() && room.lobby.is_empty() {
+                self.release_room(room_id);
+            } else {
+                self.send_lobby_positions(room_id).await;
+            }
+        }
+
+        !room_ids.is_empty()
+    }
+}
+
+// Messages
+
+pub struct StateAnswerKnockMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub target_id: Uuid,
+    pub approve: bool,
+}
+
+pub struct StateSetRoomAccessMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    // None uses the server default
+    pub max_users: Option<usize>,
+    pub require_approval: bool,
+}
+
+impl Handler<StateAnswerKnockMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateAnswerKnockMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let room = self.rooms.get_mut(&message.room_id).ok_or(anyhow!("Room not found"))?;
+
+        if room.users.get(&message.user_id).is_none_or(|user| user.role < Role::Moderator) {
+            return Err(anyhow!("Only the host or moderators can answer knocks"));
+        }
+
+        let index = room.lobby.iter()
+            .position(|entry| entry.user_id == message.target_id && !entry.approved)
+            .ok_or(anyhow!("User is not knocking"))?;
+
+        if message.approve {
+            room.lobby[index].approved = true;
+        } else {
+            room.lobby.remove(index);
+        }
+
+        let staff: Vec<Uuid> = room.users.iter()
+            .filter(|(_, user)| user.role >= Role::Moderator)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let resolved = ServerMsg::KnockResolved { user_id: message.target_id, approved: message.approve };
+
+        for user_id in staff {
+            self.send_to_user(user_id, &resolved).await;
+        }
+
+        if !message.approve {
+            let rejected = ServerMsg::JoinRejected { reason: "Your request to join was declined".to_string() };
+            self.send_to_user(message.target_id, &rejected).await;
+        }
+
+        self.admit_from_lobbies().await;
+        self.send_lobby_positions(&message.room_id).await;
+
+        Ok(())
+    }
+}
+
+impl Handler<StateSetRoomAccessMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateSetRoomAccessMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let update = RoomSettingsUpdate {
+            max_users: Some(message.max_users.unwrap_or(self.config.room_max_users)),
+            require_approval: Some(message.require_approval),
+            ..Default::default()
+        };
+
+        self.update_room_settings(&message.room_id, message.user_id, update).await
+    }
+}
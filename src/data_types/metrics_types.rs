@@ -0,0 +1,37 @@
+use serde::Serialize;
+use xtra::prelude::*;
+
+use super::state_types::JvsState;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ServerMetrics {
+    pub rooms: usize,
+    // Rooms waiting for their users to come back, included in rooms
+    pub empty_rooms: usize,
+    pub users: usize,
+    pub connections: usize,
+    pub empty_room_ttl_secs: u64
+}
+
+// Messages
+
+pub struct StateGetMetricsMessage;
+
+impl Handler<StateGetMetricsMessage> for JvsState {
+    type Return = ServerMetrics;
+
+    async fn handle(
+        &mut self,
+        _message: StateGetMetricsMessage,
+        _ctx: &mut Context<Self>,
+    ) -> ServerMetrics {
+        ServerMetrics {
+            rooms: self.rooms.len(),
+            empty_rooms: self.rooms.values().filter(|room| room.emptied_at.is_some()).count(),
+            users: self.total_users(),
+            connections: self.connections.len(),
+            empty_room_ttl_secs: self.config.empty_room_ttl_secs
+        }
+    }
+}
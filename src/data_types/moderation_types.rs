@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::msg_types::ServerMsg;
+use super::state_types::JvsState;
+
+// Ordered by privilege, so a user can only moderate users with a lower role
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    Viewer,
+    Moderator,
+    Host,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub user_id: Uuid,
+    pub resume_token: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+impl Ban {
+    pub fn matches(&self, user_id: Uuid, resume_token: Option<&str>, ip: Option<IpAddr>) -> bool {
+        self.user_id == user_id
+            || (self.resume_token.is_some() && self.resume_token.as_deref() == resume_token)
+            || (self.ip.is_some() && self.ip == ip)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ModerationAction {
+    Kick,
+    // Bans the user id and resume token, plus the ip address when ip is set
+    Ban { ip: bool },
+    // Setting both to false unmutes the user
+    Mute { chat: bool, controls: bool },
+}
+
+// Messages
+
+pub struct StateModerateMessage {
+    pub room_id: String,
+    pub moderator_id: Uuid,
+    pub target_id: Uuid,
+    pub action: ModerationAction,
+    pub reason: Option<String>,
+}
+
+pub struct StateSetModeratorMessage {
+    pub room_id: String,
+    pub host_id: Uuid,
+    pub target_id: Uuid,
+    pub moderator: bool,
+}
+
+impl Handler<StateModerateMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateModerateMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let room = self.rooms.get_mut(&message.room_id).ok_or(anyhow!("Room not found"))?;
+        let moderator = room.member(message.moderator_id).ok_or(anyhow!("You are not in this room"))?;
+        let target = room.member(message.target_id).ok_or(anyhow!("User not found"))?;
+
+        if moderator.role < Role::Moderator || moderator.role <= target.role {
+            return Err(anyhow!("Not allowed to moderate this user"));
+        }
+
+        let event = ServerMsg::ModerationEvent {
+            action: message.action.clone(),
+            target: target.into(),
+            moderator: moderator.into(),
+            reason: message.reason.clone(),
+        };
+
+        match message.action {
+            ModerationAction::Kick => {
+                self.broadcast(&message.room_id, &event, None).await;
+                self.disconnect(message.target_id, message.reason.as_deref().unwrap_or("Kicked from the room")).await;
+            },
+            ModerationAction::Ban { ip } => {
+                let user = &room.users[&message.target_id];
+                let ip = if ip { self.connections.get(&message.target_id).map(|connection| connection.ip) } else { None };
+
+                room.bans.push(Ban {
+                    user_id: message.target_id,
+                    resume_token: user.resume_token.clone(),
+                    ip,
+                });
+
+                self.broadcast(&message.room_id, &event, None).await;
+                self.disconnect(message.target_id, message.reason.as_deref().unwrap_or("Banned from the room")).await;
+            },
+            ModerationAction::Mute { chat, controls } => {
+                let user = room.users.get_mut(&message.target_id).unwrap();
+
+                user.muted_chat = chat;
+                user.muted_controls = controls;
+
+                self.broadcast(&message.room_id, &event, None).await;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+impl Handler<StateSetModeratorMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateSetModeratorMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let room = self.rooms.get_mut(&message.room_id).ok_or(anyhow!("Room not found"))?;
+
+        if room.users.get(&message.host_id).map(|user| user.role) != Some(Role::Host) {
+            return Err(anyhow!("Only the host can change roles"));
+        }
+
+        let target = room.users.get_mut(&message.target_id).ok_or(anyhow!("User not found"))?;
+
+        if target.role == Role::Host {
+            return Err(anyhow!("The host role cannot be changed"));
+        }
+
+        target.role = if message.moderator { Role::Moderator } else { Role::Viewer };
+
+        let changed = ServerMsg::RoleChanged { user_id: message.target_id, role: target.role };
+        self.broadcast(&message.room_id, &changed, None).await;
+
+        Ok(())
+    }
+}
@@ -1,31 +1,282 @@
 use serde::{Serialize, Deserialize};
+use uuid::Uuid;
 
-use super::state_types::HistoryEntry;
+use super::directory_types::RoomDirectoryPage;
+use super::moderation_types::{ModerationAction, Role};
+use super::playback_types::Correction;
+use super::presence_types::{PlaybackStatus, PresenceEntry};
+use super::queue_mode_types::{QueueModes, RepeatMode};
+use super::settings_types::{RoomSettings, RoomSettingsUpdate};
+use super::state_types::{HistoryEntry, Member, UserRef, VideoMetadata};
+
+// Fields that can be sent along with any client message. A requestId string can be attached as well,
+// the server then answers with an Ack once the message is handled
+#[derive(Deserialize, Debug)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct ClientEnvelope {
+    // Last playback seq the client has seen, lets the server detect stale commands
+    #[serde(default)]
+    pub last_seq: Option<u64>,
+    #[serde(flatten)]
+    pub msg: ClientMsg,
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all(deserialize = "camelCase"), rename_all_fields = "camelCase")]
 pub enum ClientMsg {
     SetName { name: String, room_id: String },
     SetReady {room_id: String},
-    SendToRoom { room_id: String},
+    // Host only, starts the video without waiting for everyone to be ready
+    StartNow { room_id: String },
+    SendToRoom {
+        room_id: String,
+        // Persistent client token, used to recognize the user when reconnecting
+        #[serde(default)]
+        resume_token: Option<String>
+    },
     SetVideo { url: String, room_id: String },
-    SetPlaying { status: bool, room_id: String },
+    SetPlaying {
+        status: bool,
+        // Position of the sender, used to line up the other users
+        #[serde(default)]
+        time: Option<f64>,
+        room_id: String
+    },
     Seeked { time: f64, room_id: String },
     SetPlaybackRate { rate: f32, room_id: String },
-    Rewind { seconds: u8, room_id: String },
-    Pong
+    Rewind { seconds: u32, room_id: String },
+    ReplayHistoryEntry { entry_id: Uuid, room_id: String },
+    RemoveHistoryEntry { entry_id: Uuid, room_id: String },
+    ClearHistory { room_id: String },
+    PinHistoryEntry { entry_id: Uuid, pinned: bool, room_id: String },
+    // Sent by clients that noticed a gap in the revision numbers
+    RequestSnapshot { room_id: String },
+    QueueVideo { url: String, room_id: String },
+    RemoveQueuedVideo { entry_id: Uuid, room_id: String },
+    VoteSkip { room_id: String },
+    RetractVote { room_id: String },
+    SendChat { message: String, room_id: String },
+    Moderate {
+        user_id: Uuid,
+        #[serde(flatten)]
+        action: ModerationAction,
+        #[serde(default)]
+        reason: Option<String>,
+        room_id: String
+    },
+    SetModerator { user_id: Uuid, moderator: bool, room_id: String },
+    SetStatus { status: PlaybackStatus, room_id: String },
+    SetRoomPublic { public: bool, room_id: String },
+    SetRoomAccess {
+        #[serde(default)]
+        max_users: Option<usize>,
+        require_approval: bool,
+        room_id: String
+    },
+    AnswerKnock { user_id: Uuid, approve: bool, room_id: String },
+    ListRooms {
+        #[serde(default)]
+        query: Option<String>,
+        #[serde(default)]
+        page: Option<usize>,
+        #[serde(default)]
+        page_size: Option<usize>
+    },
+    // Sent periodically so the server can correct drifting clients
+    PlaybackReport {
+        position: f64,
+        playing: bool,
+        #[serde(default)]
+        buffering: bool,
+        rate: f32,
+        room_id: String
+    },
+    GetRoomSettings { room_id: String },
+    // Host or moderators only
+    UpdateRoomSettings { settings: RoomSettingsUpdate, room_id: String },
+    // The current video reached its end
+    VideoEnded { room_id: String },
+    // Host only. Editing a scheduled party without urls keeps its videos
+    ScheduleWatchParty {
+        // Unix timestamp in milliseconds
+        start_at: u64,
+        #[serde(default)]
+        urls: Option<Vec<String>>,
+        room_id: String
+    },
+    CancelWatchParty { room_id: String },
+    SetRepeatMode { mode: RepeatMode, room_id: String },
+    SetShuffle {
+        enabled: bool,
+        // Picked by the server when missing
+        #[serde(default)]
+        seed: Option<u32>,
+        room_id: String
+    },
+    Buffering { room_id: String },
+    BufferingEnded { room_id: String },
+    SetClientOptions {
+        #[serde(default)]
+        echo_own_actions: Option<bool>,
+        #[serde(default)]
+        system_notices: Option<bool>
+    },
+    // client_time is the client clock in milliseconds
+    TimeSync { client_time: u64 },
+    // Echoes the nonce of the Ping it answers
+    Pong {
+        #[serde(default)]
+        nonce: Option<u64>
+    }
+}
+
+impl ClientMsg {
+    // Returns the room of the messages that control the playback
+    pub fn controlled_room(&self) -> Option<&str> {
+        match self {
+            ClientMsg::SetVideo { room_id, .. }
+            | ClientMsg::SetPlaying { room_id, .. }
+            | ClientMsg::Seeked { room_id, .. }
+            | ClientMsg::SetPlaybackRate { room_id, .. }
+            | ClientMsg::Rewind { room_id, .. }
+            | ClientMsg::ReplayHistoryEntry { room_id, .. }
+            | ClientMsg::RemoveHistoryEntry { room_id, .. }
+            | ClientMsg::ClearHistory { room_id }
+            | ClientMsg::PinHistoryEntry { room_id, .. }
+            | ClientMsg::RemoveQueuedVideo { room_id, .. }
+            | ClientMsg::QueueVideo { room_id, .. }
+            | ClientMsg::SetRepeatMode { room_id, .. }
+            | ClientMsg::SetShuffle { room_id, .. } => Some(room_id),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
 #[serde(tag = "type", rename_all(serialize = "camelCase"), rename_all_fields = "camelCase")]
 pub enum ServerMsg {
-    SetPlaying { status: bool },
-    ConnectedClients { clients: Vec<String> },
-    SetVideo { video_id: String, is_restricted_video: bool },
-    UpdateHistory { history: Vec<HistoryEntry> },
-    Seeked { time: f64 },
-    UnlockSetVideo,
-    SetPlaybackRate { rate: f32 },
-    Rewind { seconds: u8, should_announce: bool },
-    Ping
+    // Commands with execute_at (server clock, milliseconds) should run at that instant, time is the position then
+    // actor is the user that triggered the action, None when the server did.
+    // seq grows with every playback change, clients should ignore messages older than the last one they applied
+    SetPlaying {
+        status: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        time: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execute_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        actor: Option<UserRef>,
+        seq: u64
+    },
+    // Full snapshot of the members, only sent on join or when requested.
+    // Kept for older clients, Presence carries the same members with more details
+    ConnectedClients { clients: Vec<String>, members: Vec<Member>, revision: u64 },
+    Presence { members: Vec<PresenceEntry>, revision: u64 },
+    PresenceChanged { user: PresenceEntry, revision: u64 },
+    SetVideo {
+        video_id: String,
+        is_restricted_video: bool,
+        #[serde(flatten)]
+        metadata: Box<VideoMetadata>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execute_at: Option<u64>,
+        seq: u64
+    },
+    // Full snapshot of the history, only sent on join or when requested
+    UpdateHistory { history: Vec<HistoryEntry>, revision: u64 },
+    HistoryAppended { entry: Box<HistoryEntry>, revision: u64 },
+    HistoryRemoved { entry_id: Uuid, revision: u64 },
+    HistoryPinned { entry_id: Uuid, pinned: bool, revision: u64 },
+    // Removes every unpinned entry
+    HistoryCleared { revision: u64 },
+    UserJoined { user: Member, revision: u64 },
+    UserLeft { user_id: Uuid, revision: u64 },
+    UserRenamed { user_id: Uuid, name: String, revision: u64 },
+    UpdateQueue { queue: Vec<HistoryEntry> },
+    SkipVoteProgress { votes: usize, required: usize },
+    Chat { user: UserRef, message: String, sent_at: u64 },
+    ModerationEvent {
+        #[serde(flatten)]
+        action: ModerationAction,
+        target: UserRef,
+        moderator: UserRef,
+        reason: Option<String>
+    },
+    RoleChanged { user_id: Uuid, role: Role },
+    JoinRejected { reason: String },
+    NameRejected { reason: String },
+    // Position is None while the knock waits for an answer
+    LobbyPosition { position: Option<usize>, awaiting_approval: bool },
+    KnockRequest { user_id: Uuid },
+    KnockResolved { user_id: Uuid, approved: bool },
+    RoomList {
+        #[serde(flatten)]
+        page: RoomDirectoryPage
+    },
+    Seeked {
+        time: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execute_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        actor: Option<UserRef>,
+        seq: u64
+    },
+    // Answers a message that carried a request id
+    Ack { request_id: String, ok: bool, error: Option<String> },
+    SetPlaybackRate {
+        rate: f32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execute_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        actor: Option<UserRef>,
+        seq: u64
+    },
+    // should_announce tells clients to play the rewind announcement, naming the actor
+    Rewind {
+        seconds: u32,
+        should_announce: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execute_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        actor: Option<UserRef>,
+        seq: u64
+    },
+    // Readable description of an action, only sent to clients that enabled them
+    SystemNotice { message: String, sent_at: u64 },
+    // Sent to a single client that drifted away from the room
+    Resync {
+        time: f64,
+        playing: bool,
+        rate: f32,
+        #[serde(flatten)]
+        correction: Correction,
+        seq: u64
+    },
+    // The video started before these users were ready
+    StartedWithoutReady { users: Vec<UserRef>, timed_out: bool },
+    // The room is paused until these users finish buffering
+    BufferingPause { users: Vec<UserRef> },
+    BufferingResumed,
+    // Sent to a user the room stopped waiting for
+    BufferingLeftBehind { waited_secs: u64 },
+    RoomSettings { settings: RoomSettings },
+    RoomSettingsChanged { settings: RoomSettings },
+    // Ticks every second before the room starts playing once everyone is ready. For a watch party it is
+    // sent on join and whenever the party is scheduled or edited, with its start time and videos
+    Countdown {
+        seconds_left: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        start_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        entries: Option<Vec<HistoryEntry>>
+    },
+    CountdownCancelled,
+    // Sent on join too
+    QueueModesChanged { modes: QueueModes },
+    WatchPartyStarted,
+    WatchPartyCancelled,
+    // Lets the client estimate its clock offset: ((server_receive - client_time) + (server_transmit - now)) / 2
+    TimeSync { client_time: u64, server_receive: u64, server_transmit: u64 },
+    // sent_at is the server time in milliseconds
+    Ping { nonce: u64, sent_at: u64 }
 }
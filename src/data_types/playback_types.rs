@@ -0,0 +1,479 @@
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use tokio::sync::oneshot;
+use tokio::time;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::utils::{format_timestamp, now_millis};
+
+use super::msg_types::ServerMsg;
+use super::presence_types::PlaybackStatus;
+use super::state_types::JvsState;
+
+// Speed change used to nudge drifting clients, as a fraction of the room rate
+const NUDGE_FACTOR: f64 = 0.05;
+// Bigger drifts would take too long to nudge away, so the auto strategy seeks them
+const MAX_NUDGE_DRIFT: f64 = 2.0;
+// Reports sent while a client is still seeking are ignored
+const SEEK_GRACE: Duration = Duration::from_secs(1);
+// Scheduled commands leave the slowest client this much time on top of its delay
+const SCHEDULE_MARGIN: Duration = Duration::from_millis(50);
+const MAX_SCHEDULE_LEAD: Duration = Duration::from_secs(1);
+const REWIND_SECONDS_RANGE: std::ops::RangeInclusive<u32> = 1..=600;
+const RATE_RANGE: std::ops::RangeInclusive<f32> = 0.25..=2.0;
+
+// Far past the longest videos and streams, anything bigger is a broken or malicious client
+const MAX_POSITION: f64 = 10_000_000.0;
+
+fn is_valid_position(time: f64) -> bool {
+    (0.0..=MAX_POSITION).contains(&time)
+}
+
+// Server side clock of the room's playback
+#[derive(Debug, Clone)]
+pub struct PlaybackState {
+    pub playing: bool,
+    // Position in seconds at updated_at
+    pub position: f64,
+    pub rate: f32,
+    // In the future while a scheduled command is pending
+    pub updated_at: Instant,
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        PlaybackState {
+            playing: false,
+            position: 0.0,
+            rate: 1.0,
+            updated_at: Instant::now(),
+        }
+    }
+}
+
+impl PlaybackState {
+    pub fn position_at(&self, at: Instant) -> f64 {
+        if self.playing {
+            self.position + at.saturating_duration_since(self.updated_at).as_secs_f64() * self.rate as f64
+        } else {
+            self.position
+        }
+    }
+
+    pub fn position_now(&self) -> f64 {
+        self.position_at(Instant::now())
+    }
+
+    // The setters apply the change at the given instant, which may be in the future
+    pub fn set_position(&mut self, position: f64, at: Instant) {
+        self.position = position.max(0.0);
+        self.updated_at = at;
+    }
+
+    pub fn set_playing(&mut self, playing: bool, at: Instant) {
+        self.position = self.position_at(at);
+        self.playing = playing;
+        self.updated_at = at;
+    }
+
+    pub fn set_rate(&mut self, rate: f32, at: Instant) {
+        self.position = self.position_at(at);
+        self.rate = rate;
+        self.updated_at = at;
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncStrategy {
+    // Always jump to the room position
+    Seek,
+    // Speed up or slow down the client until it catches up
+    Nudge,
+    // Nudge small drifts and seek the big ones
+    #[default]
+    Auto,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RewindAnnouncements {
+    Off,
+    // Once per video, and again after the cooldown
+    #[default]
+    Once,
+    Always,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "correction", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Correction {
+    Seek,
+    // Play at nudge_rate for duration_ms, then go back to the room rate
+    Nudge { nudge_rate: f32, duration_ms: u64 },
+}
+
+// Resolves once a coalesced command was applied, or merged into a later one
+pub type PendingOutcome = oneshot::Receiver<Result<()>>;
+
+// Command waiting for the end of the coalescing window
+#[derive(Debug)]
+pub struct PendingControl {
+    pub user_id: Uuid,
+    pub command: PlaybackCommand,
+    pub received_at: Instant,
+    pub outcome: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaybackCommand {
+    // time is the sender's position when it started or paused the video
+    SetPlaying { status: bool, time: Option<f64> },
+    Seek { time: f64 },
+    SetRate { rate: f32 },
+    Rewind { seconds: u32 },
+}
+
+impl PlaybackCommand {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            PlaybackCommand::Rewind { seconds } if !REWIND_SECONDS_RANGE.contains(seconds) => Err(anyhow!(
+                "Rewinds must be between {} and {} seconds",
+                REWIND_SECONDS_RANGE.start(),
+                REWIND_SECONDS_RANGE.end()
+            )),
+            PlaybackCommand::SetRate { rate } if !RATE_RANGE.contains(rate) => Err(anyhow!(
+                "The playback rate must be between {} and {}",
+                RATE_RANGE.start(),
+                RATE_RANGE.end()
+            )),
+            PlaybackCommand::Seek { time } | PlaybackCommand::SetPlaying { time: Some(time), .. } if !is_valid_position(*time) => {
+                Err(anyhow!("Invalid video position"))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl JvsState {
+    // Picks when a room command should run, far enough ahead to reach every user in time.
+    // Returns the instant and the matching server timestamp in milliseconds
+    pub(super) fn schedule(&self, room_id: &str) -> (Instant, u64) {
+        let max_delay = self.rooms.get(room_id)
+            .map(|room| room.users.keys().map(|user_id| self.one_way_delay(*user_id)).fold(0.0, f64::max))
+            .unwrap_or_default();
+
+        let lead = (Duration::from_secs_f64(max_delay) + SCHEDULE_MARGIN).min(MAX_SCHEDULE_LEAD);
+
+        (Instant::now() + lead, now_millis() + lead.as_millis() as u64)
+    }
+
+    // Plays or pauses the room from its own clock
+    pub(super) async fn set_room_playing(&mut self, room_id: &str, status: bool) {
+        let (at, execute_at) = self.schedule(room_id);
+
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        room.playback.set_playing(status, at);
+
+        let payload = ServerMsg::SetPlaying {
+            status,
+            time: Some(room.playback.position),
+            execute_at: Some(execute_at),
+            actor: None,
+            seq: room.next_seq(None)
+        };
+        self.broadcast(room_id, &payload, None).await;
+    }
+}
+
+// Messages
+
+pub struct StatePlaybackMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub command: PlaybackCommand,
+    // Last sequence number the sender had seen
+    pub last_seq: Option<u64>,
+}
+
+// Sent once the coalescing window of a room ends
+pub struct StateFlushControlsMessage {
+    pub room_id: String,
+}
+
+pub struct StatePlaybackReportMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub position: f64,
+    pub playing: bool,
+    pub buffering: bool,
+    pub rate: f32,
+}
+
+impl JvsState {
+    // Checks the command against the changes the sender had not seen yet. Stale seeks are rejected and the sender is
+    // moved back to the room, while the other commands are rebased on the current state
+    async fn rebase_stale(&mut self, room_id: &str, user_id: Uuid, command: PlaybackCommand, last_seq: Option<u64>) -> Result<PlaybackCommand> {
+        let sender_delay = self.one_way_delay(user_id);
+        let room = self.rooms.get(room_id).ok_or(anyhow!("Room not found"))?;
+
+        // Only the changes of others count, the sender's own ones are applied in order anyway
+        let stale = last_seq.is_some_and(|last_seq| last_seq < room.last_foreign_seq(user_id));
+
+        if !stale {
+            return Ok(command);
+        }
+
+        match command {
+            PlaybackCommand::Seek { .. } => {
+                let clock = &room.playback;
+                let time = clock.position_now() + if clock.playing { sender_delay * clock.rate as f64 } else { 0.0 };
+                let resync = ServerMsg::Resync { time, playing: clock.playing, rate: clock.rate, correction: Correction::Seek, seq: room.seq };
+
+                self.send_to_user(user_id, &resync).await;
+                Err(anyhow!("The position was changed by someone else"))
+            },
+            PlaybackCommand::SetPlaying { status, .. } => Ok(PlaybackCommand::SetPlaying { status, time: None }),
+            // Rate changes and rewinds do not depend on the position the sender saw
+            PlaybackCommand::SetRate { .. } | PlaybackCommand::Rewind { .. } => Ok(command),
+        }
+    }
+
+    // Applies the command to the room clock and broadcasts it. received_at is when the server got the command
+    async fn apply_playback(&mut self, room_id: &str, user_id: Uuid, command: PlaybackCommand, received_at: Instant) -> Result<()> {
+        let sender_delay = self.one_way_delay(user_id);
+        let (at, execute_at) = self.schedule(room_id);
+        let rewind_cooldown = Duration::from_secs(self.config.rewind_announce_cooldown_secs);
+
+        // Playing or pausing by hand overrides the wait for buffering users
+        if let PlaybackCommand::SetPlaying { .. } = command {
+            self.clear_buffering(room_id);
+        }
+
+        // Taking over the playback ends the countdown to play
+        if !matches!(command, PlaybackCommand::SetRate { .. }) {
+            self.cancel_play_countdown(room_id).await;
+        }
+
+        let room = self.rooms.get_mut(room_id).ok_or(anyhow!("Room not found"))?;
+
+        let Some(actor) = room.user_ref(user_id) else {
+            return Err(anyhow!("User not found"));
+        };
+
+        let seq = room.next_seq(Some(user_id));
+
+        // The sender's player keeps running until execute_at, so line everyone up with where it will be then
+        let lead = at.saturating_duration_since(received_at).as_secs_f64();
+        let ahead = |time: f64, playing: bool, rate: f32| if playing { time + (sender_delay + lead) * rate as f64 } else { time };
+
+        let (payload, notice) = match command {
+            PlaybackCommand::SetPlaying { status, time } => {
+                if let Some(time) = time {
+                    room.playback.set_position(ahead(time, status, room.playback.rate), at);
+                }
+
+                room.playback.set_playing(status, at);
+
+                let position = room.playback.position;
+                let notice = if status {
+                    format!("{} played at {}", actor.name, format_timestamp(position))
+                } else {
+                    format!("{} paused at {}", actor.name, format_timestamp(position))
+                };
+
+                (ServerMsg::SetPlaying { status, time: Some(position), execute_at: Some(execute_at), actor: Some(actor), seq }, notice)
+            },
+            PlaybackCommand::Seek { time } => {
+                room.playback.set_position(ahead(time, room.playback.playing, room.playback.rate), at);
+
+                let position = room.playback.position;
+                let notice = format!("{} jumped to {}", actor.name, format_timestamp(position));
+
+                (ServerMsg::Seeked { time: position, execute_at: Some(execute_at), actor: Some(actor), seq }, notice)
+            },
+            PlaybackCommand::SetRate { rate } => {
+                room.playback.set_rate(rate, at);
+
+                let notice = format!("{} set the speed to {}x", actor.name, rate);
+                (ServerMsg::SetPlaybackRate { rate, execute_at: Some(execute_at), actor: Some(actor), seq }, notice)
+            },
+            PlaybackCommand::Rewind { seconds } => {
+                let position = (room.playback.position_at(at) - seconds as f64).max(0.0);
+                room.playback.set_position(position, at);
+
+                let should_announce = match room.settings.rewind_announcements {
+                    RewindAnnouncements::Off => false,
+                    RewindAnnouncements::Once => room.rewind_announced_at.is_none_or(|announced_at| announced_at.elapsed() >= rewind_cooldown),
+                    RewindAnnouncements::Always => true,
+                };
+
+                if should_announce {
+                    room.rewind_announced_at = Some(Instant::now());
+                }
+
+                let notice = format!("{} rewound {} seconds", actor.name, seconds);
+                (ServerMsg::Rewind { seconds, should_announce, execute_at: Some(execute_at), actor: Some(actor), seq }, notice)
+            },
+        };
+
+        self.broadcast_action(room_id, &payload, user_id).await;
+        self.send_system_notice(room_id, notice).await;
+
+        Ok(())
+    }
+}
+
+// Returns the outcome to wait for when the command was held back for coalescing
+impl Handler<StatePlaybackMessage> for JvsState {
+    type Return = Result<Option<PendingOutcome>>;
+
+    async fn handle(
+        &mut self,
+        message: StatePlaybackMessage,
+        ctx: &mut Context<Self>,
+    ) -> Result<Option<PendingOutcome>> {
+        message.command.validate()?;
+
+        let party_pending = self.rooms.get(&message.room_id).is_some_and(|room| room.watch_party.is_some());
+
+        if party_pending && matches!(message.command, PlaybackCommand::SetPlaying { status: true, .. }) {
+            return Err(anyhow!("The watch party has not started yet"));
+        }
+
+        let command = self.rebase_stale(&message.room_id, message.user_id, message.command, message.last_seq).await?;
+        let window = Duration::from_millis(self.config.control_coalesce_ms);
+
+        let coalesce = !window.is_zero() && matches!(command, PlaybackCommand::Seek { .. } | PlaybackCommand::SetPlaying { .. });
+
+        if !coalesce {
+            return self.apply_playback(&message.room_id, message.user_id, command, Instant::now()).await.map(|_| None);
+        }
+
+        let room = self.rooms.get_mut(&message.room_id).ok_or(anyhow!("Room not found"))?;
+        let first = room.pending_controls.is_empty();
+
+        // Only the last seek and the last play state of the window are kept, the replaced one counts as done
+        let (kept, replaced): (Vec<PendingControl>, Vec<PendingControl>) = std::mem::take(&mut room.pending_controls).into_iter()
+            .partition(|pending| std::mem::discriminant(&pending.command) != std::mem::discriminant(&command));
+
+        for pending in replaced {
+            let _ = pending.outcome.send(Ok(()));
+        }
+
+        let (outcome, receiver) = oneshot::channel();
+        room.pending_controls = kept;
+        room.pending_controls.push(PendingControl { user_id: message.user_id, command, received_at: Instant::now(), outcome });
+
+        if first {
+            let address = ctx.mailbox().address();
+            let room_id = message.room_id;
+
+            tokio::spawn(async move {
+                time::sleep(window).await;
+                let _ = address.send(StateFlushControlsMessage { room_id }).await;
+            });
+        }
+
+        Ok(Some(receiver))
+    }
+}
+
+impl Handler<StateFlushControlsMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StateFlushControlsMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(room) = self.rooms.get_mut(&message.room_id) else {
+            return;
+        };
+
+        let pending = std::mem::take(&mut room.pending_controls);
+
+        for control in pending {
+            let result = self.apply_playback(&message.room_id, control.user_id, control.command, control.received_at).await;
+            let _ = control.outcome.send(result);
+        }
+    }
+}
+
+impl Handler<StatePlaybackReportMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StatePlaybackReportMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        if !is_valid_position(message.position) || !RATE_RANGE.contains(&message.rate) {
+            return Err(anyhow!("Invalid playback report"));
+        }
+
+        let delay = self.one_way_delay(message.user_id);
+
+        let room = self.rooms.get_mut(&message.room_id).ok_or(anyhow!("Room not found"))?;
+        let user = room.users.get_mut(&message.user_id).ok_or(anyhow!("User not found"))?;
+
+        let status = match (message.buffering, message.playing) {
+            (true, _) => PlaybackStatus::Buffering,
+            (false, true) => PlaybackStatus::Playing,
+            (false, false) => PlaybackStatus::Paused,
+        };
+
+        let status_changed = user.status != status;
+        user.status = status;
+
+        // Reports are unreliable while loading, buffering or applying a previous correction
+        let correcting = user.resync_until.is_some_and(|until| Instant::now() < until);
+        let skip = room.now_playing.is_none() || room.awaiting_ready || message.buffering || correcting;
+
+        let clock = &room.playback;
+        let rate = clock.rate as f64;
+
+        // Both positions as they will be when a correction reaches the client
+        let expected = clock.position_now() + if clock.playing { delay * rate } else { 0.0 };
+        let actual = message.position + if message.playing { 2.0 * delay * message.rate as f64 } else { 0.0 };
+        let drift = expected - actual;
+
+        let mismatch = message.playing != clock.playing || (message.rate - clock.rate).abs() > 0.01;
+
+        // Too long (or impossible) nudges fall back to a seek
+        let nudge_duration = Duration::try_from_secs_f64(drift.abs() / (rate * NUDGE_FACTOR)).ok()
+            .filter(|duration| rate > 0.0 && duration.as_secs_f64() <= MAX_NUDGE_DRIFT / NUDGE_FACTOR);
+
+        let correction = if skip || (!mismatch && drift.abs() <= room.settings.drift_tolerance) {
+            None
+        } else if mismatch || !clock.playing || room.settings.sync_strategy == SyncStrategy::Seek
+            || (room.settings.sync_strategy == SyncStrategy::Auto && drift.abs() > MAX_NUDGE_DRIFT) {
+            Some((Correction::Seek, SEEK_GRACE))
+        } else if let Some(duration) = nudge_duration {
+            let nudge_rate = rate * (1.0 + NUDGE_FACTOR * drift.signum());
+
+            Some((Correction::Nudge { nudge_rate: nudge_rate as f32, duration_ms: duration.as_millis() as u64 }, duration))
+        } else {
+            Some((Correction::Seek, SEEK_GRACE))
+        };
+
+        if let Some((correction, duration)) = correction {
+            let resync = ServerMsg::Resync { time: expected, playing: clock.playing, rate: clock.rate, correction, seq: room.seq };
+            user.resync_until = Some(Instant::now() + duration);
+
+            self.send_to_user(message.user_id, &resync).await;
+        }
+
+        if status_changed {
+            self.send_presence_changed(&message.room_id, message.user_id).await;
+        }
+
+        Ok(())
+    }
+}
+
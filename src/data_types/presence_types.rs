@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::moderation_types::Role;
+use super::msg_types::ServerMsg;
+use super::state_types::JvsState;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum PlaybackStatus {
+    #[default]
+    Unknown,
+    Playing,
+    Paused,
+    Buffering,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct PresenceEntry {
+    pub id: Uuid,
+    pub name: String,
+    pub role: Role,
+    // Whether the user has loaded the current video
+    pub ready: bool,
+    // Round trip time, None until it is measured
+    pub latency_ms: Option<u32>,
+    pub status: PlaybackStatus,
+    // Unix timestamp in milliseconds
+    pub joined_at: u64,
+}
+
+impl JvsState {
+    pub(super) fn presence_entry(&self, room_id: &str, user_id: Uuid) -> Option<PresenceEntry> {
+        let user = self.rooms.get(room_id)?.users.get(&user_id)?;
+
+        Some(PresenceEntry {
+            id: user_id,
+            name: user.name.clone(),
+            role: user.role,
+            ready: user.ready,
+            latency_ms: self.connections.get(&user_id).and_then(|connection| connection.rtt_ms).map(|rtt| rtt.round() as u32),
+            status: user.status,
+            joined_at: user.joined_at,
+        })
+    }
+
+    pub(super) fn presence(&self, room_id: &str) -> Vec<PresenceEntry> {
+        let Some(room) = self.rooms.get(room_id) else {
+            return Vec::new();
+        };
+
+        room.members().into_iter().filter_map(|member| self.presence_entry(room_id, member.id)).collect()
+    }
+
+    pub(super) async fn send_presence_changed(&mut self, room_id: &str, user_id: Uuid) {
+        let Some(user) = self.presence_entry(room_id, user_id) else {
+            return;
+        };
+
+        let room = self.rooms.get_mut(room_id).unwrap();
+        let changed = ServerMsg::PresenceChanged { user, revision: room.next_revision() };
+        self.broadcast(room_id, &changed, None).await;
+    }
+}
+
+// Messages
+
+pub struct StateSetStatusMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub status: PlaybackStatus,
+}
+
+impl Handler<StateSetStatusMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StateSetStatusMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(user) = self.rooms.get_mut(&message.room_id).and_then(|room| room.users.get_mut(&message.user_id)) else {
+            return;
+        };
+
+        if user.status != message.status {
+            user.status = message.status;
+            self.send_presence_changed(&message.room_id, message.user_id).await;
+        }
+    }
+}
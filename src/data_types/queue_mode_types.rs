@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::utils::{now_millis, seeded_shuffle, splitmix64};
+
+use super::msg_types::ServerMsg;
+use super::state_types::{HistoryEntry, JvsState, Room};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    // Replay the current video when it ends
+    One,
+    // Send every finished video to the back of the queue
+    All,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct QueueModes {
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    // Seed of the shuffled order. Kept below 2^32 so javascript clients can use it as is
+    pub seed: u32,
+    // Videos queued since the shuffle started, each one gets its own seeded slot
+    #[serde(skip)]
+    pub draws: u64,
+}
+
+impl Room {
+    // Adds the entry to the queue, at a seeded position while it is shuffled
+    pub fn enqueue(&mut self, entry: HistoryEntry) {
+        if !self.queue_modes.shuffle {
+            self.queue.push_back(entry);
+            return;
+        }
+
+        let mut state = (self.queue_modes.seed as u64) ^ self.queue_modes.draws;
+        self.queue_modes.draws += 1;
+
+        let index = (splitmix64(&mut state) % (self.queue.len() as u64 + 1)) as usize;
+        self.queue.insert(index, entry);
+    }
+
+    // Puts the finished video back at the end of the queue when the whole queue loops
+    pub(super) fn requeue_finished(&mut self) {
+        if self.queue_modes.repeat != RepeatMode::All {
+            return;
+        }
+
+        let Some(finished) = self.now_playing.clone() else {
+            return;
+        };
+
+        // A new id, the history keeps the old one
+        self.enqueue(HistoryEntry { id: Uuid::new_v4(), added_at: now_millis(), ..finished });
+    }
+}
+
+impl JvsState {
+    async fn send_queue_modes(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let changed = ServerMsg::QueueModesChanged { modes: room.queue_modes };
+        self.broadcast(room_id, &changed, None).await;
+    }
+}
+
+// Messages
+
+pub struct StateSetRepeatModeMessage {
+    pub room_id: String,
+    pub mode: RepeatMode,
+}
+
+pub struct StateSetShuffleMessage {
+    pub room_id: String,
+    pub enabled: bool,
+    // None picks a random seed
+    pub seed: Option<u32>,
+}
+
+impl Handler<StateSetRepeatModeMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StateSetRepeatModeMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(room) = self.rooms.get_mut(&message.room_id) else {
+            return;
+        };
+
+        if room.queue_modes.repeat == message.mode {
+            return;
+        }
+
+        room.queue_modes.repeat = message.mode;
+        self.send_queue_modes(&message.room_id).await;
+    }
+}
+
+impl Handler<StateSetShuffleMessage> for JvsState {
+    type Return = ();
+
+    async fn handle(
+        &mut self,
+        message: StateSetShuffleMessage,
+        _ctx: &mut Context<Self>,
+    ) {
+        let Some(room) = self.rooms.get_mut(&message.room_id) else {
+            return;
+        };
+
+        if message.enabled {
+            let seed = message.seed.unwrap_or_else(|| Uuid::new_v4().as_u64_pair().0 as u32);
+
+            room.queue_modes.shuffle = true;
+            room.queue_modes.seed = seed;
+            room.queue_modes.draws = 0;
+
+            seeded_shuffle(room.queue.make_contiguous(), seed as u64);
+        } else if room.queue_modes.shuffle {
+            room.queue_modes.shuffle = false;
+
+            // Back to the order the videos were queued in
+            room.queue.make_contiguous().sort_by_key(|entry| entry.added_at);
+        } else {
+            return;
+        }
+
+        self.send_queue_modes(&message.room_id).await;
+        self.send_queue(&message.room_id).await;
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::moderation_types::Role;
+use super::msg_types::ServerMsg;
+use super::state_types::{JvsState, UserRef};
+
+const COUNTDOWN_SECS: u64 = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PlayCountdown {
+    pub seconds_left: u64,
+    // When the next tick is sent, or the video started once none are left
+    pub next_at: Instant,
+}
+
+impl JvsState {
+    // Ends the ready check and starts the video, telling the room who was not ready yet
+    async fn start_after_ready_check(&mut self, room_id: &str, timed_out: bool) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        room.awaiting_ready = false;
+        room.ready_deadline = None;
+
+        let not_ready: Vec<UserRef> = room.members().into_iter()
+            .filter(|member| room.users.get(&member.id).is_some_and(|user| !user.ready))
+            .map(UserRef::from)
+            .collect();
+
+        if !not_ready.is_empty() {
+            self.broadcast(room_id, &ServerMsg::StartedWithoutReady { users: not_ready, timed_out }, None).await;
+        }
+
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        // A scheduled watch party only starts at its start time
+        if room.watch_party.is_some() {
+            return;
+        }
+
+        if room.settings.ready_countdown {
+            room.play_countdown = Some(PlayCountdown { seconds_left: COUNTDOWN_SECS, next_at: Instant::now() + Duration::from_secs(1) });
+
+            let countdown = ServerMsg::Countdown { seconds_left: COUNTDOWN_SECS, start_at: None, entries: None };
+            self.broadcast(room_id, &countdown, None).await;
+        } else {
+            self.set_room_playing(room_id, true).await;
+        }
+    }
+
+    // Stops the countdown to play, e.g. when someone pauses or seeks before it ends
+    pub(super) async fn cancel_play_countdown(&mut self, room_id: &str) {
+        if self.rooms.get_mut(room_id).and_then(|room| room.play_countdown.take()).is_some() {
+            self.broadcast(room_id, &ServerMsg::CountdownCancelled, None).await;
+        }
+    }
+
+    // Sends the next tick of the running countdowns and starts the rooms whose countdown ended
+    pub(super) async fn run_play_countdowns(&mut self) {
+        let now = Instant::now();
+
+        let due: Vec<String> = self.rooms.iter()
+            .filter(|(_, room)| room.play_countdown.is_some_and(|countdown| countdown.next_at <= now))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+
+        for room_id in due {
+            let Some(countdown) = self.rooms.get_mut(&room_id).and_then(|room| room.play_countdown.as_mut()) else {
+                continue;
+            };
+
+            countdown.seconds_left -= 1;
+            countdown.next_at += Duration::from_secs(1);
+
+            if countdown.seconds_left == 0 {
+                self.rooms.get_mut(&room_id).unwrap().play_countdown = None;
+                self.set_room_playing(&room_id, true).await;
+            } else {
+                let countdown = ServerMsg::Countdown { seconds_left: countdown.seconds_left, start_at: None, entries: None };
+                self.broadcast(&room_id, &countdown, None).await;
+            }
+        }
+    }
+
+    // Starts the video once every user in the room is ready
+    pub(super) async fn check_ready(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        if room.awaiting_ready && room.users.values().all(|user| user.ready) {
+            self.start_after_ready_check(room_id, false).await;
+        }
+    }
+
+    // Starts the rooms whose ready check ran out of time
+    pub(super) async fn expire_ready_checks(&mut self) {
+        let now = Instant::now();
+
+        let expired: Vec<String> = self.rooms.iter()
+            .filter(|(_, room)| room.awaiting_ready && room.ready_deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+
+        for room_id in expired {
+            self.start_after_ready_check(&room_id, true).await;
+        }
+    }
+}
+
+// Messages
+
+pub struct StateStartNowMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+}
+
+impl Handler<StateStartNowMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateStartNowMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let room = self.rooms.get(&message.room_id).ok_or(anyhow!("Room not found"))?;
+
+        if room.users.get(&message.user_id).map(|user| user.role) != Some(Role::Host) {
+            return Err(anyhow!("Only the host can start the video before everyone is ready"));
+        }
+
+        if !room.awaiting_ready {
+            return Err(anyhow!("The room is not waiting for anyone"));
+        }
+
+        self.start_after_ready_check(&message.room_id, false).await;
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct YoutubeDataResponse {
@@ -9,16 +9,68 @@ pub struct YoutubeDataResponse {
 pub struct YoutubeDataItem {
     pub snippet: Snippet,
     #[serde(rename = "contentDetails")]
-    pub content_details: ContentDetails
+    pub content_details: ContentDetails,
+    // Only present when the request asks for the status part
+    pub status: Option<Status>
 }
 
 #[derive(Deserialize)]
 pub struct Snippet {
-    pub title: String
+    pub title: String,
+    #[serde(rename = "channelTitle", default)]
+    pub channel_title: String,
+    #[serde(default)]
+    pub thumbnails: Thumbnails,
+    #[serde(rename = "liveBroadcastContent", default)]
+    pub live_broadcast_content: LiveBroadcastContent
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Thumbnails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<Thumbnail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub medium: Option<Thumbnail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub high: Option<Thumbnail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub standard: Option<Thumbnail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxres: Option<Thumbnail>
+}
+
+impl Thumbnails {
+    // Medium sized thumbnails are enough for lists, fall back to whatever is available
+    pub fn preferred(&self) -> Option<&Thumbnail> {
+        self.medium.as_ref()
+            .or(self.high.as_ref())
+            .or(self.default.as_ref())
+            .or(self.standard.as_ref())
+            .or(self.maxres.as_ref())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Thumbnail {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum LiveBroadcastContent {
+    #[default]
+    None,
+    Live,
+    Upcoming
 }
 
 #[derive(Deserialize)]
 pub struct ContentDetails {
+    // ISO-8601 duration, e.g. "PT4M13S". Live streams report "P0D"
+    #[serde(default)]
+    pub duration: String,
     #[serde(rename = "contentRating")]
     pub content_rating: ContentRating
 }
@@ -27,4 +79,14 @@ pub struct ContentDetails {
 pub struct ContentRating {
     #[serde(rename = "ytRating")]
     pub yt_rating: Option<String>
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize)]
+pub struct Status {
+    #[serde(default = "default_embeddable")]
+    pub embeddable: bool
+}
+
+fn default_embeddable() -> bool {
+    true
+}
@@ -0,0 +1,243 @@
+use std::ops::RangeInclusive;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use super::config_types::ServerConfig;
+use super::moderation_types::Role;
+use super::msg_types::ServerMsg;
+use super::playback_types::{RewindAnnouncements, SyncStrategy};
+use super::state_types::{JvsState, Room};
+
+const DRIFT_TOLERANCE_RANGE: RangeInclusive<f64> = 0.1..=10.0;
+
+// Who can control the playback and the videos of a room
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum ControlPolicy {
+    #[default]
+    Everyone,
+    Moderators,
+    Host,
+}
+
+impl ControlPolicy {
+    pub fn min_role(&self) -> Role {
+        match self {
+            ControlPolicy::Everyone => Role::Viewer,
+            ControlPolicy::Moderators => Role::Moderator,
+            ControlPolicy::Host => Role::Host,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RestrictedContent {
+    // Age restricted videos cannot be played or queued
+    #[default]
+    Block,
+    Allow,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct RoomSettings {
+    pub control_policy: ControlPolicy,
+    // Play the next queued video when the current one ends
+    pub autoplay: bool,
+    pub max_users: usize,
+    // Users past the first one wait in the lobby until a moderator lets them in
+    pub require_approval: bool,
+    // Listed in the public room directory
+    pub public: bool,
+    pub restricted_content: RestrictedContent,
+    // Moderators can still chat when it is disabled
+    pub chat_enabled: bool,
+    // Seconds a client can drift before it is resynced
+    pub drift_tolerance: f64,
+    pub sync_strategy: SyncStrategy,
+    // Pause the room while someone is buffering
+    pub pause_on_buffering: bool,
+    pub rewind_announcements: RewindAnnouncements,
+    // Count down from 3 before starting a video everyone is ready for
+    pub ready_countdown: bool,
+}
+
+impl RoomSettings {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        RoomSettings {
+            control_policy: ControlPolicy::default(),
+            autoplay: true,
+            max_users: config.room_max_users,
+            require_approval: false,
+            public: false,
+            restricted_content: RestrictedContent::default(),
+            chat_enabled: true,
+            drift_tolerance: config.drift_tolerance_secs,
+            sync_strategy: SyncStrategy::default(),
+            pause_on_buffering: false,
+            rewind_announcements: RewindAnnouncements::default(),
+            ready_countdown: false,
+        }
+    }
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings::from_config(&ServerConfig::default())
+    }
+}
+
+// Fields left as None keep their current value
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct RoomSettingsUpdate {
+    pub control_policy: Option<ControlPolicy>,
+    pub autoplay: Option<bool>,
+    pub max_users: Option<usize>,
+    pub require_approval: Option<bool>,
+    pub public: Option<bool>,
+    pub restricted_content: Option<RestrictedContent>,
+    pub chat_enabled: Option<bool>,
+    pub drift_tolerance: Option<f64>,
+    pub sync_strategy: Option<SyncStrategy>,
+    pub pause_on_buffering: Option<bool>,
+    pub rewind_announcements: Option<RewindAnnouncements>,
+    pub ready_countdown: Option<bool>,
+}
+
+impl RoomSettingsUpdate {
+    // Who can join and find the room is up to the host alone
+    fn changes_access(&self) -> bool {
+        self.max_users.is_some() || self.require_approval.is_some() || self.public.is_some()
+    }
+}
+
+impl Room {
+    pub fn new(config: &ServerConfig) -> Self {
+        Room { settings: RoomSettings::from_config(config), ..Default::default() }
+    }
+}
+
+impl JvsState {
+    // Validates and applies the update, then broadcasts the new settings to the room
+    pub(super) async fn update_room_settings(&mut self, room_id: &str, user_id: Uuid, update: RoomSettingsUpdate) -> Result<()> {
+        let room_max_users = self.config.room_max_users;
+        let room = self.rooms.get_mut(room_id).ok_or(anyhow!("Room not found"))?;
+        let role = room.users.get(&user_id).map(|user| user.role);
+
+        if role.is_none_or(|role| role < Role::Moderator) {
+            return Err(anyhow!("Only the host or moderators can change the room settings"));
+        }
+
+        if update.changes_access() && role != Some(Role::Host) {
+            return Err(anyhow!("Only the host can change the room access"));
+        }
+
+        if update.max_users.is_some_and(|max_users| max_users == 0 || max_users > room_max_users) {
+            return Err(anyhow!("Maximum users must be between 1 and {}", room_max_users));
+        }
+
+        if update.drift_tolerance.is_some_and(|drift_tolerance| !DRIFT_TOLERANCE_RANGE.contains(&drift_tolerance)) {
+            return Err(anyhow!(
+                "Drift tolerance must be between {} and {} seconds",
+                DRIFT_TOLERANCE_RANGE.start(),
+                DRIFT_TOLERANCE_RANGE.end()
+            ));
+        }
+
+        let settings = &mut room.settings;
+
+        if let Some(control_policy) = update.control_policy {
+            settings.control_policy = control_policy;
+        }
+        if let Some(autoplay) = update.autoplay {
+            settings.autoplay = autoplay;
+        }
+        if let Some(max_users) = update.max_users {
+            settings.max_users = max_users;
+        }
+        if let Some(require_approval) = update.require_approval {
+            settings.require_approval = require_approval;
+        }
+        if let Some(public) = update.public {
+            settings.public = public;
+        }
+        if let Some(restricted_content) = update.restricted_content {
+            settings.restricted_content = restricted_content;
+        }
+        if let Some(chat_enabled) = update.chat_enabled {
+            settings.chat_enabled = chat_enabled;
+        }
+        if let Some(drift_tolerance) = update.drift_tolerance {
+            settings.drift_tolerance = drift_tolerance;
+        }
+        if let Some(sync_strategy) = update.sync_strategy {
+            settings.sync_strategy = sync_strategy;
+        }
+        if let Some(pause_on_buffering) = update.pause_on_buffering {
+            settings.pause_on_buffering = pause_on_buffering;
+
+            // Nobody should stay paused waiting on a disabled option
+            if !pause_on_buffering {
+                room.buffering.clear();
+            }
+        }
+        if let Some(rewind_announcements) = update.rewind_announcements {
+            settings.rewind_announcements = rewind_announcements;
+        }
+        if let Some(ready_countdown) = update.ready_countdown {
+            settings.ready_countdown = ready_countdown;
+        }
+
+        let changed = ServerMsg::RoomSettingsChanged { settings: room.settings.clone() };
+        self.broadcast(room_id, &changed, None).await;
+
+        self.check_buffering(room_id).await;
+
+        // A bigger or open room may have room for the people waiting
+        if update.max_users.is_some() || update.require_approval.is_some() {
+            self.admit_from_lobbies().await;
+        }
+
+        Ok(())
+    }
+}
+
+// Messages
+
+pub struct StateGetRoomSettingsMessage {
+    pub room_id: String,
+}
+
+pub struct StateUpdateRoomSettingsMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    pub update: RoomSettingsUpdate,
+}
+
+impl Handler<StateGetRoomSettingsMessage> for JvsState {
+    type Return = Option<RoomSettings>;
+
+    async fn handle(
+        &mut self,
+        message: StateGetRoomSettingsMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Option<RoomSettings> {
+        self.rooms.get(&message.room_id).map(|room| room.settings.clone())
+    }
+}
+
+impl Handler<StateUpdateRoomSettingsMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateUpdateRoomSettingsMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        self.update_room_settings(&message.room_id, message.user_id, message.update).await
+    }
+}
@@ -1,197 +1,965 @@
-use std::collections::HashMap;
-use serde::Serialize;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
-use futures_util::{stream::SplitSink, SinkExt};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::tungstenite::Message;
+use futures_util::SinkExt;
 use uuid::Uuid;
 use xtra::prelude::*;
 
+use crate::names::{normalize_name, random_name, unique_name};
+use crate::utils::{now_millis, parse_iso8601_duration};
+
+use super::config_types::ServerConfig;
+use super::connection_types::Connection;
+use super::lobby_types::LobbyEntry;
+use super::moderation_types::{Ban, Role};
+use super::playback_types::{PendingControl, PlaybackState};
+use super::presence_types::PlaybackStatus;
+use super::queue_mode_types::{QueueModes, RepeatMode};
+use super::ready_types::PlayCountdown;
+use super::settings_types::RoomSettings;
+use super::watch_party_types::WatchParty;
 use super::msg_types::ServerMsg;
+use super::response_types::{LiveBroadcastContent, Thumbnails, YoutubeDataItem};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoMetadata {
+    pub title: String,
+    pub channel_title: String,
+    // None when the duration is unknown or the video is a live stream
+    pub duration_seconds: Option<u64>,
+    pub thumbnails: Thumbnails,
+    pub live_broadcast_content: LiveBroadcastContent,
+    pub embeddable: bool,
+    pub age_restricted: bool
+}
 
-#[derive(Debug, Clone, Serialize)]
-#[serde(rename_all(serialize = "camelCase"))]
+impl From<&YoutubeDataItem> for VideoMetadata {
+    fn from(item: &YoutubeDataItem) -> Self {
+        let duration_seconds = match item.snippet.live_broadcast_content {
+            LiveBroadcastContent::None => parse_iso8601_duration(&item.content_details.duration),
+            _ => None,
+        };
+
+        VideoMetadata {
+            title: item.snippet.title.clone(),
+            channel_title: item.snippet.channel_title.clone(),
+            duration_seconds,
+            thumbnails: item.snippet.thumbnails.clone(),
+            live_broadcast_content: item.snippet.live_broadcast_content,
+            embeddable: item.status.as_ref().is_none_or(|status| status.embeddable),
+            // ytRating is only present on age restricted videos
+            age_restricted: item.content_details.content_rating.yt_rating.is_some()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRef {
+    pub id: Uuid,
+    pub name: String
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct HistoryEntry {
+    pub id: Uuid,
     pub url: String,
     pub video_id: String,
-    pub title: String
+    #[serde(flatten)]
+    pub metadata: VideoMetadata,
+    pub added_by: Option<UserRef>,
+    // Unix timestamp in milliseconds
+    pub added_at: u64,
+    pub pinned: bool
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Member {
+    pub id: Uuid,
+    pub name: String,
+    pub role: Role
+}
+
+impl From<Member> for UserRef {
+    fn from(member: Member) -> Self {
+        UserRef { id: member.id, name: member.name }
+    }
 }
 
 #[derive(Debug)]
 pub struct User {
     pub name: String,
+    pub role: Role,
+    // Unix timestamp in milliseconds
+    pub joined_at: u64,
+    // Client provided token that identifies the user across reconnections
+    pub resume_token: Option<String>,
+    pub muted_chat: bool,
+    pub muted_controls: bool,
+    // Whether the user has loaded the current video
+    pub ready: bool,
+    pub status: PlaybackStatus,
+    // Set while the client applies a Resync, its reports are ignored until then
+    pub resync_until: Option<Instant>,
 }
 
 #[derive(Debug, Default)]
 pub struct Room {
     pub users: HashMap<Uuid, User>,
-    pub ready_count: u32,
+    // Set when the video changes, until every user is ready to play it
+    pub awaiting_ready: bool,
+    // The video starts anyway once it passes
+    pub ready_deadline: Option<Instant>,
+    // Running countdown before the video starts, see RoomSettings::ready_countdown
+    pub play_countdown: Option<PlayCountdown>,
     pub current_video: String,
-    pub rewind_alert_played: bool,
-    pub history: Vec<HistoryEntry>
+    pub now_playing: Option<HistoryEntry>,
+    // Set once a client reported the end of the current video
+    pub video_ended: bool,
+    pub settings: RoomSettings,
+    pub playback: PlaybackState,
+    // Seeks and play state changes of the current coalescing window
+    pub pending_controls: Vec<PendingControl>,
+    // Users the room is paused for, with the time they started buffering
+    pub buffering: HashMap<Uuid, Instant>,
+    // Whether the room is currently paused because of buffering users
+    pub buffering_pause: bool,
+    // Last announced rewind of the current video
+    pub rewind_announced_at: Option<Instant>,
+    pub history: Vec<HistoryEntry>,
+    // Upcoming videos, played in order when the room advances
+    pub queue: VecDeque<HistoryEntry>,
+    pub queue_modes: QueueModes,
+    pub skip_votes: HashSet<Uuid>,
+    pub bans: Vec<Ban>,
+    // Users waiting for a free slot or for their knock to be answered, in arrival order
+    pub lobby: VecDeque<LobbyEntry>,
+    // Bumped on every history or member list change so clients can detect missed deltas
+    pub revision: u64,
+    // Bumped on every playback change (video, play state, position, rate) so clients can drop stale messages
+    pub seq: u64,
+    // Last change of the two most recent distinct actors (None for the server), newest first.
+    // Enough to find the last change made by anyone but a given user
+    pub seq_actors: Vec<(Option<Uuid>, u64)>,
+    // Set while nobody is in the room, it is deleted once the empty room ttl passes
+    pub emptied_at: Option<Instant>,
+    pub watch_party: Option<WatchParty>
+}
+
+impl Room {
+    pub fn user_ref(&self, user_id: Uuid) -> Option<UserRef> {
+        self.users.get(&user_id).map(|user| UserRef { id: user_id, name: user.name.clone() })
+    }
+
+    pub fn member(&self, user_id: Uuid) -> Option<Member> {
+        self.users.get(&user_id).map(|user| Member { id: user_id, name: user.name.clone(), role: user.role })
+    }
+
+    pub fn members(&self) -> Vec<Member> {
+        let mut users: Vec<(&Uuid, &User)> = self.users.iter().collect();
+        users.sort_by_key(|(_, user)| user.joined_at);
+
+        users.into_iter().map(|(id, user)| Member { id: *id, name: user.name.clone(), role: user.role }).collect()
+    }
+
+    pub fn can_control(&self, user_id: Uuid) -> bool {
+        self.users.get(&user_id).is_some_and(|user| !user.muted_controls && user.role >= self.settings.control_policy.min_role())
+    }
+
+    pub fn next_revision(&mut self) -> u64 {
+        self.revision += 1;
+        self.revision
+    }
+
+    pub fn next_seq(&mut self, actor: Option<Uuid>) -> u64 {
+        self.seq += 1;
+
+        self.seq_actors.retain(|(seq_actor, _)| *seq_actor != actor);
+        self.seq_actors.insert(0, (actor, self.seq));
+        self.seq_actors.truncate(2);
+
+        self.seq
+    }
+
+    // Sequence number of the last playback change made by someone else than the user
+    pub fn last_foreign_seq(&self, user_id: Uuid) -> u64 {
+        self.seq_actors.iter()
+            .find(|(actor, _)| *actor != Some(user_id))
+            .map_or(0, |(_, seq)| *seq)
+    }
+
+    // Appends to the history, skipping consecutive repeats and evicting the oldest unpinned entries past max_length.
+    // Returns the ids of the evicted entries, or None if the entry was a repeat
+    pub fn push_history(&mut self, entry: HistoryEntry, max_length: usize) -> Option<Vec<Uuid>> {
+        if self.history.last().is_some_and(|last| last.video_id == entry.video_id) {
+            return None;
+        }
+
+        self.history.push(entry);
+
+        let mut evicted = Vec::new();
+
+        while self.history.len() > max_length {
+            match self.history.iter().position(|entry| !entry.pinned) {
+                Some(index) => evicted.push(self.history.remove(index).id),
+                None => break,
+            }
+        }
+
+        Some(evicted)
+    }
 }
 
 #[derive(Debug, Default, xtra::Actor)]
 pub struct JvsState {
+    pub config: ServerConfig,
     pub rooms: HashMap<String, Room>,
-    pub ws_clients: HashMap<Uuid, SplitSink<WebSocketStream<TcpStream>, Message>>
+    pub connections: HashMap<Uuid, Connection>
 }
 
+impl JvsState {
+    pub fn new(config: ServerConfig) -> Self {
+        let mut state = JvsState { config, ..Default::default() };
+        state.load_watch_parties();
+
+        state
+    }
+
+    pub(super) async fn send_to_user(&mut self, user_id: Uuid, message: &ServerMsg) {
+        if let Some(connection) = self.connections.get_mut(&user_id) {
+            let _ = connection.ws.send(Message::Text(serde_json::to_string(message).unwrap())).await;
+        }
+    }
+
+    pub(super) async fn broadcast(&mut self, room_id: &str, message: &ServerMsg, except: Option<Uuid>) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let text = serde_json::to_string(message).unwrap();
+        let user_ids: Vec<Uuid> = room.users.keys().filter(|id| Some(**id) != except).copied().collect();
+
+        for user_id in user_ids {
+            if let Some(connection) = self.connections.get_mut(&user_id) {
+                let _ = connection.ws.send(Message::Text(text.clone())).await;
+            }
+        }
+    }
+
+    // Closes the socket with the given reason and removes the user from its room
+    pub(super) async fn disconnect(&mut self, user_id: Uuid, reason: &str) {
+        if let Some(mut connection) = self.connections.remove(&user_id) {
+            // Close reasons are limited to 123 bytes
+            let reason: String = reason.chars().scan(0, |len, c| {
+                *len += c.len_utf8();
+                (*len <= 123).then_some(c)
+            }).collect();
+
+            let _ = connection.ws.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: Cow::Owned(reason)
+            }))).await;
+            let _ = connection.closed.send(());
+        }
+
+        self.remove_user(user_id).await;
+    }
+
+    // Removes the user from its room and the lobbies. Returns the room the user was in
+    pub(super) async fn remove_user(&mut self, user_id: Uuid) -> Option<String> {
+        self.connections.remove(&user_id);
+
+        // Users waiting in a lobby may still be in the room they came from
+        self.leave_lobbies(user_id).await;
+        self.leave_room(user_id).await
+    }
+
+    // Removes the user from its room, deleting the room once it is empty. Returns the room the user was in
+    pub(super) async fn leave_room(&mut self, user_id: Uuid) -> Option<String> {
+        let room_name = self.rooms.iter()
+            .find(|(_, room)| room.users.contains_key(&user_id))
+            .map(|(room_id, _)| room_id.clone())?;
+
+        let room = self.rooms.get_mut(&room_name).unwrap();
+        let removed_user = room.users.remove(&user_id);
+        let removed_vote = room.skip_votes.remove(&user_id);
+        room.buffering.remove(&user_id);
+
+        if room.users.is_empty() {
+            if room.lobby.is_empty() {
+                self.release_room(&room_name);
+            } else {
+                self.admit_from_lobbies().await;
+            }
+
+            return None;
+        }
+
+        // Hand the host role over to the longest present moderator, or else the longest present user
+        let new_host = match removed_user {
+            Some(user) if user.role == Role::Host => room.users.iter_mut()
+                .max_by_key(|(_, user)| (user.role, std::cmp::Reverse(user.joined_at)))
+                .map(|(id, user)| {
+                    user.role = Role::Host;
+                    *id
+                }),
+            _ => None,
+        };
+
+        let has_votes = removed_vote || !room.skip_votes.is_empty();
+        let left = ServerMsg::UserLeft { user_id, revision: room.next_revision() };
+        self.broadcast(&room_name, &left, None).await;
+
+        if let Some(new_host) = new_host {
+            self.broadcast(&room_name, &ServerMsg::RoleChanged { user_id: new_host, role: Role::Host }, None).await;
+        }
+
+        // The tally changed, and the threshold may have been reached now that there are fewer users
+        if has_votes {
+            self.check_skip_votes(&room_name).await;
+        }
+
+        // The room may have been waiting for the user to finish buffering or to get ready
+        self.check_buffering(&room_name).await;
+        self.check_ready(&room_name).await;
+
+        // A slot just freed up
+        self.admit_from_lobbies().await;
+
+        Some(room_name)
+    }
+
+    // Keeps the room of the last leaving user around for the empty room ttl, or deletes it when there is none
+    pub(super) fn release_room(&mut self, room_id: &str) {
+        // Rooms with a scheduled watch party wait for it, like in expire_empty_rooms
+        let has_party = self.rooms.get(room_id).is_some_and(|room| room.watch_party.is_some());
+
+        if self.config.empty_room_ttl_secs == 0 && !has_party {
+            self.rooms.remove(room_id);
+            return;
+        }
+
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        let now = Instant::now();
+        room.emptied_at = Some(now);
+
+        // Nobody is watching, so the video stays where it was left
+        room.playback.set_playing(false, now);
+        room.pending_controls.clear();
+        room.awaiting_ready = false;
+        room.ready_deadline = None;
+        room.play_countdown = None;
+        room.buffering.clear();
+        room.buffering_pause = false;
+    }
+
+    // Deletes the rooms that stayed empty for longer than the empty room ttl
+    pub(super) fn expire_empty_rooms(&mut self) {
+        let ttl = Duration::from_secs(self.config.empty_room_ttl_secs);
+
+        // Rooms with a scheduled watch party wait for it
+        self.rooms.retain(|_, room| room.watch_party.is_some() || room.emptied_at.is_none_or(|emptied_at| emptied_at.elapsed() < ttl));
+    }
+
+    // Adds the user to the room and sends the room state to them
+    pub(super) async fn admit_user(&mut self, room_id: &str, user_id: Uuid, resume_token: Option<String>) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        if room.users.contains_key(&user_id) {
+            return;
+        }
+
+        room.emptied_at = None;
+
+        // The first user to join a room hosts it
+        let role = if room.users.is_empty() { Role::Host } else { Role::Viewer };
+        let user = User {
+            name: unique_name(&random_name(), room.users.values().map(|user| user.name.as_str())),
+            role,
+            joined_at: now_millis(),
+            resume_token,
+            muted_chat: false,
+            muted_controls: false,
+            ready: false,
+            status: PlaybackStatus::Unknown,
+            resync_until: None
+        };
+
+        room.users.insert(user_id, user);
+
+        let joined = ServerMsg::UserJoined {
+            user: room.member(user_id).unwrap(),
+            revision: room.next_revision()
+        };
+
+        // Send the current video in room to the client when he enter the room
+        let last_video = room.now_playing.as_ref().map(|last_video| ServerMsg::SetVideo {
+            video_id: last_video.video_id.clone(),
+            is_restricted_video: last_video.metadata.age_restricted,
+            metadata: Box::new(last_video.metadata.clone()),
+            execute_at: None,
+            seq: room.seq
+        });
+
+        // Then where the room is in it, so the video does not start over at 0:00
+        let clock = &room.playback;
+        let rate = (clock.rate != 1.0).then_some(ServerMsg::SetPlaybackRate { rate: clock.rate, execute_at: None, actor: None, seq: room.seq });
+        let position = ServerMsg::SetPlaying { status: clock.playing, time: Some(clock.position_now()), execute_at: None, actor: None, seq: room.seq };
+
+        self.broadcast(room_id, &joined, Some(user_id)).await;
+        self.send_snapshot(room_id, user_id).await;
+
+        if let Some(last_video) = last_video {
+            self.send_to_user(user_id, &last_video).await;
+
+            if let Some(rate) = rate {
+                self.send_to_user(user_id, &rate).await;
+            }
+
+            self.send_to_user(user_id, &position).await;
+        }
+
+        if let Some(countdown) = self.countdown(room_id) {
+            self.send_to_user(user_id, &countdown).await;
+        }
+    }
+
+    async fn send_snapshot(&mut self, room_id: &str, user_id: Uuid) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let history = ServerMsg::UpdateHistory { history: room.history.clone(), revision: room.revision };
+        let members = room.members();
+        let clients = ServerMsg::ConnectedClients {
+            clients: members.iter().map(|member| member.name.clone()).collect(),
+            members,
+            revision: room.revision
+        };
+
+        let presence = ServerMsg::Presence { members: self.presence(room_id), revision: room.revision };
+        let queue = ServerMsg::UpdateQueue { queue: room.queue.iter().cloned().collect() };
+        let queue_modes = ServerMsg::QueueModesChanged { modes: room.queue_modes };
+
+        self.send_to_user(user_id, &history).await;
+        self.send_to_user(user_id, &clients).await;
+        self.send_to_user(user_id, &presence).await;
+        self.send_to_user(user_id, &queue).await;
+        self.send_to_user(user_id, &queue_modes).await;
+    }
+
+    // Switches the room to the entry's video and records it in the history
+    async fn play_entry(&mut self, room_id: &str, entry: HistoryEntry) {
+        let (_, execute_at) = self.schedule(room_id);
+        let ready_timeout_secs = self.config.ready_timeout_secs;
+
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        room.current_video = entry.video_id.clone();
+        room.now_playing = Some(entry.clone());
+        room.playback = PlaybackState::default();
+        room.pending_controls.clear();
+        room.rewind_announced_at = None;
+        room.video_ended = false;
+        room.buffering.clear();
+        room.buffering_pause = false;
+        room.awaiting_ready = true;
+        room.ready_deadline = Some(Instant::now() + Duration::from_secs(ready_timeout_secs));
+        room.play_countdown = None;
+
+        for user in room.users.values_mut() {
+            user.ready = false;
+        }
+
+        let payload = ServerMsg::SetVideo {
+            video_id: entry.video_id.clone(),
+            is_restricted_video: entry.metadata.age_restricted,
+            metadata: Box::new(entry.metadata.clone()),
+            execute_at: Some(execute_at),
+            seq: room.next_seq(None)
+        };
+
+        self.broadcast(room_id, &payload, None).await;
+        self.reset_skip_votes(room_id).await;
+        self.append_history(room_id, entry).await;
+    }
+
+    // Plays the next queued video, or pauses the room if the queue is empty
+    pub(super) async fn advance_queue(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        room.requeue_finished();
+
+        match room.queue.pop_front() {
+            Some(entry) => {
+                self.play_entry(room_id, entry).await;
+                self.send_queue(room_id).await;
+            },
+            None => {
+                self.reset_skip_votes(room_id).await;
+                self.set_room_playing(room_id, false).await;
+            },
+        }
+    }
+
+    pub(super) async fn send_queue(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let queue = ServerMsg::UpdateQueue { queue: room.queue.iter().cloned().collect() };
+        self.broadcast(room_id, &queue, None).await;
+    }
+
+    // Broadcasts the vote tally and skips the current video once the threshold is reached
+    async fn check_skip_votes(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+
+        let votes = room.skip_votes.len();
+        let required = self.config.skip_threshold.required_votes(room.users.len());
+
+        self.broadcast(room_id, &ServerMsg::SkipVoteProgress { votes, required }, None).await;
+
+        if votes > 0 && votes >= required {
+            self.advance_queue(room_id).await;
+        }
+    }
+
+    // Clears the votes cast against the previous video, so the clients do not keep showing its tally
+    async fn reset_skip_votes(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        if room.skip_votes.is_empty() {
+            return;
+        }
+
+        room.skip_votes.clear();
+        let required = self.config.skip_threshold.required_votes(room.users.len());
+
+        self.broadcast(room_id, &ServerMsg::SkipVoteProgress { votes: 0, required }, None).await;
+    }
+
+    // Records a new history entry and broadcasts the resulting deltas
+    async fn append_history(&mut self, room_id: &str, entry: HistoryEntry) {
+        let max_length = self.config.history_max_length;
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        let Some(evicted) = room.push_history(entry.clone(), max_length) else {
+            return;
+        };
+
+        let appended = ServerMsg::HistoryAppended { entry: Box::new(entry), revision: room.next_revision() };
+        let removed: Vec<ServerMsg> = evicted.into_iter()
+            .map(|entry_id| ServerMsg::HistoryRemoved { entry_id, revision: room.next_revision() })
+            .collect();
+
+        self.broadcast(room_id, &appended, None).await;
+
+        for message in removed {
+            self.broadcast(room_id, &message, None).await;
+        }
+    }
+}
+
+const CHAT_MAX_LENGTH: usize = 500;
+// Seconds before the end of the video a reported end is still believed
+const VIDEO_END_TOLERANCE: f64 = 5.0;
+
 // Messages
 
 pub enum StateGenericMessage {
-    InsertUser { user_id: Uuid, ws: SplitSink<WebSocketStream<TcpStream>, Message> },
+    InsertUser { user_id: Uuid, connection: Connection },
     RenameUser { user_id: Uuid, name: String, room_id: String },
-    JoinRoom { user_id: Uuid, room_id: String },
-    SetVideo { room_id: String, video_id: String, url: String, title: String },
-    SendSocketMessage { room_id: String, message: Message },
+    JoinRoom { user_id: Uuid, room_id: String, resume_token: Option<String> },
+    SendChat { user_id: Uuid, room_id: String, message: String },
+    SendSnapshot { user_id: Uuid, room_id: String },
+    SetVideo { room_id: String, video_id: String, url: String, metadata: Box<VideoMetadata>, added_by: Uuid },
+    ReplayHistoryEntry { room_id: String, entry_id: Uuid, user_id: Uuid },
+    RemoveHistoryEntry { room_id: String, entry_id: Uuid },
+    ClearHistory { room_id: String },
+    PinHistoryEntry { room_id: String, entry_id: Uuid, pinned: bool },
+    QueueVideo { room_id: String, video_id: String, url: String, metadata: Box<VideoMetadata>, added_by: Uuid },
+    RemoveQueuedVideo { room_id: String, entry_id: Uuid },
+    SkipVote { room_id: String, user_id: Uuid, vote: bool },
     SendMsgToUser { user_id: Uuid, message: ServerMsg },
 }
 
 pub struct StateSetReadyMessage {
-    pub room_id: String
+    pub room_id: String,
+    pub user_id: Uuid
 }
 
 pub struct StateGetCurrentVideoMessage {
     pub room_id: String
 }
 
-pub struct StateGetClientsMessage {
-    pub room_id: String
-}
-
-pub struct StateGetHistoryMessage {
-    pub room_id: String
+pub struct  StateRemoveUserMessage {
+    pub user_id: Uuid
 }
 
-pub struct  StateRemoveUserMessage {
+// A client finished the current video
+pub struct StateVideoEndedMessage {
+    pub room_id: String,
     pub user_id: Uuid
 }
 
-pub struct StateGetRoomShouldAnnounceRewind {
-    pub room_id: String
+pub struct StateCanControlMessage {
+    pub room_id: String,
+    pub user_id: Uuid
 }
 
 impl Handler<StateGenericMessage> for JvsState {
-    type Return = ();
+    type Return = Result<()>;
 
     async fn handle(
         &mut self,
         message: StateGenericMessage,
         _ctx: &mut Context<Self>,
-    ) {
+    ) -> Result<()> {
         match message {
-            StateGenericMessage::InsertUser { user_id, ws } => {
-                self.ws_clients.insert(user_id, ws);
+            StateGenericMessage::InsertUser { user_id, connection } => {
+                self.connections.insert(user_id, connection);
             },
             StateGenericMessage::RenameUser { user_id, name, room_id } => {
-                let room = self.rooms.get_mut(&room_id).expect("Cannot find room");
-                let user = room.users.get_mut(&user_id).expect("Cannot find user");
+                let name = match normalize_name(&name, &self.config.blocked_name_words) {
+                    Ok(name) => name,
+                    Err(error) => {
+                        let reason = error.to_string();
+                        self.send_to_user(user_id, &ServerMsg::NameRejected { reason: reason.clone() }).await;
+                        return Err(anyhow!(reason));
+                    },
+                };
 
-                user.name = name;
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                if !room.users.contains_key(&user_id) {
+                    return Err(anyhow!("You are not in this room"));
+                }
+
+                let others = room.users.iter().filter(|(id, _)| **id != user_id).map(|(_, user)| user.name.as_str());
+                let name = unique_name(&name, others);
+
+                let user = room.users.get_mut(&user_id).unwrap();
+                user.name = name.clone();
+
+                let renamed = ServerMsg::UserRenamed { user_id, name, revision: room.next_revision() };
+                self.broadcast(&room_id, &renamed, None).await;
+            },
+            StateGenericMessage::JoinRoom { user_id, room_id, resume_token } => {
+                let ip = self.connections.get(&user_id).map(|connection| connection.ip);
+                let room = self.rooms.entry(room_id.clone()).or_insert_with(|| Room::new(&self.config));
+
+                if room.bans.iter().any(|ban| ban.matches(user_id, resume_token.as_deref(), ip)) {
+                    let reason = "You are banned from this room".to_string();
+                    self.send_to_user(user_id, &ServerMsg::JoinRejected { reason: reason.clone() }).await;
+                    return Err(anyhow!(reason));
+                }
+
+                // Joining again keeps the current role and name, the client only needs the room state again
+                if room.users.contains_key(&user_id) {
+                    self.send_snapshot(&room_id, user_id).await;
+                    return Ok(());
+                }
+
+                if room.lobby.iter().any(|entry| entry.user_id == user_id) {
+                    self.send_lobby_positions(&room_id).await;
+                    return Ok(());
+                }
+
+                // Users are in one room at a time, so they leave the one they were in or waiting for
+                self.leave_lobbies(user_id).await;
+                self.leave_room(user_id).await;
+
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+                let needs_approval = room.settings.require_approval && !room.users.is_empty();
+                // Keep the line fair, nobody skips the users already waiting for a slot
+                let has_waiting = room.lobby.iter().any(|entry| entry.approved);
+
+                if !needs_approval && !has_waiting && self.has_capacity(&room_id) {
+                    self.admit_user(&room_id, user_id, resume_token).await;
+                    return Ok(());
+                }
+
+                let room = self.rooms.get_mut(&room_id).unwrap();
+                room.lobby.push_back(LobbyEntry { user_id, resume_token, approved: !needs_approval });
+
+                if needs_approval {
+                    let staff: Vec<Uuid> = room.users.iter()
+                        .filter(|(_, user)| user.role >= Role::Moderator)
+                        .map(|(id, _)| *id)
+                        .collect();
+
+                    for staff_id in staff {
+                        self.send_to_user(staff_id, &ServerMsg::KnockRequest { user_id }).await;
+                    }
+                }
+
+                self.send_lobby_positions(&room_id).await;
             },
-            StateGenericMessage::JoinRoom { user_id, room_id } => {
-                let room = self.rooms.entry(room_id).or_insert(Room::default());
-                let user = User {
-                    name: user_id.to_string()
+            StateGenericMessage::SendChat { user_id, room_id, message } => {
+                let room = self.rooms.get(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                let user = room.users.get(&user_id).ok_or(anyhow!("You are not in this room"))?;
+                let message = message.trim();
+
+                if user.muted_chat {
+                    return Err(anyhow!("You are muted in this room"));
+                }
+
+                // Moderators can still talk when the chat is disabled
+                if !room.settings.chat_enabled && user.role < Role::Moderator {
+                    return Err(anyhow!("The chat is disabled in this room"));
+                }
+
+                if message.is_empty() {
+                    return Err(anyhow!("The message is empty"));
+                }
+
+                let chat = ServerMsg::Chat {
+                    user: room.user_ref(user_id).unwrap(),
+                    message: message.chars().take(CHAT_MAX_LENGTH).collect(),
+                    sent_at: now_millis()
                 };
 
-                room.users.insert(user_id, user);
+                self.broadcast(&room_id, &chat, None).await;
+            },
+            StateGenericMessage::SendSnapshot { user_id, room_id } => {
+                self.send_snapshot(&room_id, user_id).await;
             },
-            StateGenericMessage::SetVideo { room_id, video_id, url, title } => {
-                let room = self.rooms.get_mut(&room_id).expect("Cannot find room");
+            StateGenericMessage::SetVideo { room_id, video_id, url, metadata, added_by } => {
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
 
-                room.current_video = video_id.clone();
-                room.ready_count = 0;
-                room.history.push(HistoryEntry {
+                let entry = HistoryEntry {
+                    id: Uuid::new_v4(),
                     url,
-                    video_id: video_id.clone(),
-                    title: title
-                });
+                    video_id,
+                    metadata: *metadata,
+                    added_by: room.user_ref(added_by),
+                    added_at: now_millis(),
+                    pinned: false
+                };
+
+                self.play_entry(&room_id, entry).await;
             },
-            StateGenericMessage::SendSocketMessage { room_id, message } => {
-                let room = self.rooms.get_mut(&room_id).expect("Failed to get users");
+            StateGenericMessage::ReplayHistoryEntry { room_id, entry_id, user_id } => {
+                let room = self.rooms.get(&room_id).ok_or(anyhow!("Room not found"))?;
 
-                for (key, _client) in room.users.iter_mut() {
-                    let ws = self.ws_clients.get_mut(key).expect("Failed to find socket");
+                let entry = room.history.iter().find(|entry| entry.id == entry_id).ok_or(anyhow!("History entry not found"))?;
 
-                    let _ = ws.send(message.clone()).await;
+                if room.current_video == entry.video_id {
+                    return Ok(());
                 }
+
+                let replayed = HistoryEntry {
+                    id: Uuid::new_v4(),
+                    added_by: room.user_ref(user_id),
+                    added_at: now_millis(),
+                    pinned: false,
+                    ..entry.clone()
+                };
+
+                self.play_entry(&room_id, replayed).await;
             },
-            StateGenericMessage::SendMsgToUser { user_id, message } => {
-                let ws_user = self.ws_clients.get_mut(&user_id).expect("Failed to find user");
+            StateGenericMessage::RemoveHistoryEntry { room_id, entry_id } => {
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                if !room.history.iter().any(|entry| entry.id == entry_id) {
+                    return Err(anyhow!("History entry not found"));
+                }
 
-                let _ = ws_user.send(Message::Text(serde_json::to_string(&message).unwrap())).await;
+                room.history.retain(|entry| entry.id != entry_id);
+
+                let removed = ServerMsg::HistoryRemoved { entry_id, revision: room.next_revision() };
+                self.broadcast(&room_id, &removed, None).await;
+            },
+            StateGenericMessage::ClearHistory { room_id } => {
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                // Pinned favorites survive a clear
+                room.history.retain(|entry| entry.pinned);
+
+                let cleared = ServerMsg::HistoryCleared { revision: room.next_revision() };
+                self.broadcast(&room_id, &cleared, None).await;
+            },
+            StateGenericMessage::PinHistoryEntry { room_id, entry_id, pinned } => {
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                let entry = room.history.iter_mut().find(|entry| entry.id == entry_id).ok_or(anyhow!("History entry not found"))?;
+                entry.pinned = pinned;
+
+                let pinned = ServerMsg::HistoryPinned { entry_id, pinned, revision: room.next_revision() };
+                self.broadcast(&room_id, &pinned, None).await;
+            },
+            StateGenericMessage::QueueVideo { room_id, video_id, url, metadata, added_by } => {
+                let queue_max_length = self.config.queue_max_length;
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                if room.queue.len() >= queue_max_length {
+                    return Err(anyhow!("The queue can have at most {} videos", queue_max_length));
+                }
+
+                let entry = HistoryEntry {
+                    id: Uuid::new_v4(),
+                    url,
+                    video_id,
+                    metadata: *metadata,
+                    added_by: room.user_ref(added_by),
+                    added_at: now_millis(),
+                    pinned: false
+                };
+
+                room.enqueue(entry);
+                self.send_queue(&room_id).await;
+            },
+            StateGenericMessage::RemoveQueuedVideo { room_id, entry_id } => {
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                if !room.queue.iter().any(|entry| entry.id == entry_id) {
+                    return Err(anyhow!("Queued video not found"));
+                }
+
+                room.queue.retain(|entry| entry.id != entry_id);
+                self.send_queue(&room_id).await;
+            },
+            StateGenericMessage::SkipVote { room_id, user_id, vote } => {
+                let room = self.rooms.get_mut(&room_id).ok_or(anyhow!("Room not found"))?;
+
+                if !room.users.contains_key(&user_id) {
+                    return Err(anyhow!("You are not in this room"));
+                }
+
+                let changed = if vote {
+                    room.skip_votes.insert(user_id)
+                } else {
+                    room.skip_votes.remove(&user_id)
+                };
+
+                if changed {
+                    self.check_skip_votes(&room_id).await;
+                }
+            },
+            StateGenericMessage::SendMsgToUser { user_id, message } => {
+                self.send_to_user(user_id, &message).await;
             },
         };
+
+        Ok(())
     }
 }
 
 impl Handler<StateSetReadyMessage> for JvsState {
-    type Return = bool;
+    type Return = ();
 
     async fn handle(
         &mut self,
         message: StateSetReadyMessage,
         _ctx: &mut Context<Self>,
-    ) -> bool {
-        let room = self.rooms.get_mut(&message.room_id).expect("Cannot find room");
-
-        room.ready_count += 1;
-
-        if room.ready_count == room.users.len() as u32 {
-            room.ready_count = 0;
+    ) {
+        let Some(user) = self.rooms.get_mut(&message.room_id).and_then(|room| room.users.get_mut(&message.user_id)) else {
+            return;
+        };
 
-            return true;
+        if !user.ready {
+            user.ready = true;
+            self.send_presence_changed(&message.room_id, message.user_id).await;
         }
 
-        false
+        self.check_ready(&message.room_id).await;
     }
 }
 
 impl Handler<StateGetCurrentVideoMessage> for JvsState {
-    type Return = String;
+    type Return = Option<String>;
 
     async fn handle(
         &mut self,
         message: StateGetCurrentVideoMessage,
         _ctx: &mut Context<Self>,
-    ) -> String {
-        let room = self.rooms.get_mut(&message.room_id).expect("Cannot find room");
-
-        room.current_video.clone()
+    ) -> Option<String> {
+        self.rooms.get(&message.room_id).map(|room| room.current_video.clone())
     }
 }
 
-impl Handler<StateGetClientsMessage> for JvsState {
-    type Return = Vec<String>;
+impl Handler<StateVideoEndedMessage> for JvsState {
+    type Return = ();
 
     async fn handle(
         &mut self,
-        message: StateGetClientsMessage,
+        message: StateVideoEndedMessage,
         _ctx: &mut Context<Self>,
-    ) -> Vec<String> {
-        let room = self.rooms.get_mut(&message.room_id).expect("Cannot find room");
-        let mut connected_clients: Vec<String> = Vec::new();
+    ) {
+        let Some(room) = self.rooms.get_mut(&message.room_id) else {
+            return;
+        };
+
+        // Every client reports the end, only the first report counts
+        if room.video_ended || !room.users.contains_key(&message.user_id) {
+            return;
+        }
+
+        // The server clock has to agree, so nobody can skip the video by reporting its end early
+        let Some(duration) = room.now_playing.as_ref().and_then(|entry| entry.metadata.duration_seconds) else {
+            return;
+        };
 
-        for (_key, client) in room.users.iter() {
-            connected_clients.push(client.name.clone());
+        if room.playback.position_now() < duration as f64 - VIDEO_END_TOLERANCE {
+            return;
         }
 
-        connected_clients
+        room.video_ended = true;
+
+        if room.queue_modes.repeat == RepeatMode::One {
+            // Back to the start, paused until the scheduled play below
+            let now = Instant::now();
+            room.video_ended = false;
+            room.playback.set_playing(false, now);
+            room.playback.set_position(0.0, now);
+
+            self.set_room_playing(&message.room_id, true).await;
+        } else if room.settings.autoplay {
+            self.advance_queue(&message.room_id).await;
+        } else {
+            self.set_room_playing(&message.room_id, false).await;
+        }
     }
 }
 
-impl Handler<StateGetHistoryMessage> for JvsState {
-    type Return = Vec<HistoryEntry>;
+// Sent periodically to run the timeouts
+pub struct StateTickMessage;
+
+impl Handler<StateTickMessage> for JvsState {
+    type Return = ();
 
     async fn handle(
         &mut self,
-        message: StateGetHistoryMessage,
+        _message: StateTickMessage,
         _ctx: &mut Context<Self>,
-    ) -> Vec<HistoryEntry> {
-        let room = self.rooms.get_mut(&message.room_id).expect("Cannot find room");
-
-        room.history.clone()
+    ) {
+        self.expire_buffering().await;
+        self.expire_ready_checks().await;
+        self.run_play_countdowns().await;
+        self.run_watch_parties().await;
+        self.expire_empty_rooms();
     }
 }
 
@@ -203,51 +971,44 @@ impl Handler<StateRemoveUserMessage> for JvsState {
         message: StateRemoveUserMessage,
         _ctx: &mut Context<Self>,
     ) -> Option<String> {
-        self.ws_clients.remove(&message.user_id);
-
-        let rooms = self.rooms.iter_mut();
-
-        let mut room_name = String::default();
-
-        for (key, value) in rooms {
-            if value.users.contains_key(&message.user_id) {
-                value.users.remove(&message.user_id);
-
-                room_name = key.to_string();
-            }
-        }
-
-        let room = self.rooms.get(&room_name);
-
-        if let Some(room) = room {
-            if room.users.len() == 0 {
-                self.rooms.remove(&room_name);
-                return None;
-            }
-
-            Some(room_name)
-        } else {
-            None
-        }
+        self.remove_user(message.user_id).await
     }
 }
 
-impl Handler<StateGetRoomShouldAnnounceRewind> for JvsState {
+impl Handler<StateCanControlMessage> for JvsState {
     type Return = bool;
 
     async fn handle(
         &mut self,
-        message: StateGetRoomShouldAnnounceRewind,
+        message: StateCanControlMessage,
         _ctx: &mut Context<Self>,
     ) -> bool {
-        let room = self.rooms.get_mut(&message.room_id).expect("Cannot find room");
+        self.rooms.get(&message.room_id).is_some_and(|room| room.can_control(message.user_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let current_state = room.rewind_alert_played;
+    #[tokio::test]
+    async fn removes_users_waiting_in_a_lobby_from_their_room() {
+        let mut state = JvsState::new(ServerConfig::default());
+        let (user_id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
 
-        if !current_state {
-            room.rewind_alert_played = true
+        for room_id in ["a", "b"] {
+            state.rooms.insert(room_id.to_string(), Room::new(&state.config));
         }
 
-        !current_state
+        state.admit_user("a", user_id, None).await;
+        state.admit_user("b", other_id, None).await;
+        state.rooms.get_mut("b").unwrap().lobby.push_back(LobbyEntry { user_id, resume_token: None, approved: false });
+
+        state.remove_user(user_id).await;
+
+        let room = &state.rooms["a"];
+        assert!(room.users.is_empty());
+        assert!(room.emptied_at.is_some());
+        assert!(state.rooms["b"].lobby.is_empty());
     }
 }
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::time::Instant;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xtra::prelude::*;
+
+use crate::utils::now_millis;
+
+use super::moderation_types::Role;
+use super::msg_types::ServerMsg;
+use super::state_types::{HistoryEntry, JvsState, Room, VideoMetadata};
+
+// How long before the start the videos are loaded, so everyone is ready on time
+const PRELOAD_LEAD_MS: u64 = 30_000;
+const MAX_PARTY_VIDEOS: usize = 50;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchParty {
+    // Unix timestamp in milliseconds
+    pub start_at: u64,
+    // Played in order once the party starts, empty keeps the room's own video and queue
+    pub entries: Vec<HistoryEntry>,
+    // Set once the videos were loaded ahead of the start
+    #[serde(skip)]
+    pub preloaded: bool,
+}
+
+pub struct ScheduledVideo {
+    pub video_id: String,
+    pub url: String,
+    pub metadata: Box<VideoMetadata>,
+}
+
+impl JvsState {
+    // Checked before any video is fetched, so rejected requests do not spend api quota
+    fn check_can_schedule(&self, room_id: &str, user_id: Uuid, videos: Option<usize>) -> Result<()> {
+        let room = self.rooms.get(room_id).ok_or(anyhow!("Room not found"))?;
+
+        if room.users.get(&user_id).map(|user| user.role) != Some(Role::Host) {
+            return Err(anyhow!("Only the host can schedule a watch party"));
+        }
+
+        if videos.is_some_and(|videos| videos > MAX_PARTY_VIDEOS) {
+            return Err(anyhow!("A watch party can have at most {} videos", MAX_PARTY_VIDEOS));
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn countdown(&self, room_id: &str) -> Option<ServerMsg> {
+        let party = self.rooms.get(room_id)?.watch_party.as_ref()?;
+
+        Some(ServerMsg::Countdown {
+            seconds_left: party.start_at.saturating_sub(now_millis()).div_ceil(1000),
+            start_at: Some(party.start_at),
+            entries: Some(party.entries.clone())
+        })
+    }
+
+    // Restores the scheduled watch parties saved before a restart
+    pub(super) fn load_watch_parties(&mut self) {
+        let Some(path) = self.config.persistence_file.as_ref() else {
+            return;
+        };
+
+        let parties: HashMap<String, WatchParty> = match std::fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(parties) => parties,
+                Err(err) => {
+                    println!("Could not parse the persistence file: {}", err);
+                    return;
+                },
+            },
+            // Nothing was saved yet
+            Err(_) => return,
+        };
+
+        for (room_id, party) in parties {
+            let mut room = Room::new(&self.config);
+            room.watch_party = Some(party);
+            room.emptied_at = Some(Instant::now());
+
+            self.rooms.insert(room_id, room);
+        }
+    }
+
+    pub(super) async fn save_watch_parties(&self) {
+        let Some(path) = self.config.persistence_file.as_ref() else {
+            return;
+        };
+
+        let parties: HashMap<&String, &WatchParty> = self.rooms.iter()
+            .filter_map(|(room_id, room)| room.watch_party.as_ref().map(|party| (room_id, party)))
+            .collect();
+
+        if let Err(err) = tokio::fs::write(path, serde_json::to_string(&parties).unwrap()).await {
+            println!("Could not write the persistence file: {}", err);
+        }
+    }
+
+    // Loads the party videos shortly before the start, playback stays paused until then
+    async fn preload_watch_party(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+        let Some(party) = room.watch_party.as_mut() else {
+            return;
+        };
+
+        party.preloaded = true;
+        let entries = party.entries.clone();
+
+        if entries.is_empty() && room.now_playing.is_some() {
+            return;
+        }
+
+        for entry in entries.into_iter().rev() {
+            room.queue.push_front(entry);
+        }
+
+        if !room.queue.is_empty() {
+            self.advance_queue(room_id).await;
+        }
+    }
+
+    async fn start_watch_party(&mut self, room_id: &str) {
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+
+        room.watch_party = None;
+        room.awaiting_ready = false;
+        room.ready_deadline = None;
+
+        // Give the latecomers of a party nobody showed up to the whole empty room ttl
+        if room.emptied_at.is_some() {
+            room.emptied_at = Some(Instant::now());
+        }
+
+        let has_video = room.now_playing.is_some();
+
+        self.broadcast(room_id, &ServerMsg::WatchPartyStarted, None).await;
+
+        if has_video {
+            self.set_room_playing(room_id, true).await;
+        }
+
+        self.save_watch_parties().await;
+    }
+
+    // Preloads and starts the watch parties whose time has come
+    pub(super) async fn run_watch_parties(&mut self) {
+        let now = now_millis();
+
+        let due: Vec<(String, bool)> = self.rooms.iter()
+            .filter_map(|(room_id, room)| room.watch_party.as_ref().map(|party| (room_id, party)))
+            .filter(|(_, party)| (!party.preloaded && party.start_at <= now + PRELOAD_LEAD_MS) || party.start_at <= now)
+            .map(|(room_id, party)| (room_id.clone(), party.start_at <= now))
+            .collect();
+
+        for (room_id, started) in due {
+            if self.rooms.get(&room_id).and_then(|room| room.watch_party.as_ref()).is_some_and(|party| !party.preloaded) {
+                self.preload_watch_party(&room_id).await;
+            }
+
+            if started {
+                self.start_watch_party(&room_id).await;
+            }
+        }
+    }
+}
+
+// Messages
+
+pub struct StateScheduleWatchPartyMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    // Unix timestamp in milliseconds
+    pub start_at: u64,
+    // None keeps the videos of the current schedule when editing it
+    pub videos: Option<Vec<ScheduledVideo>>,
+}
+
+pub struct StateCanScheduleMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+    // Number of videos the party will have, None keeps the current ones
+    pub videos: Option<usize>,
+}
+
+pub struct StateCancelWatchPartyMessage {
+    pub room_id: String,
+    pub user_id: Uuid,
+}
+
+impl Handler<StateScheduleWatchPartyMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateScheduleWatchPartyMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        self.check_can_schedule(&message.room_id, message.user_id, message.videos.as_ref().map(Vec::len))?;
+
+        if message.start_at <= now_millis() {
+            return Err(anyhow!("The watch party must start in the future"));
+        }
+
+        let room = self.rooms.get_mut(&message.room_id).unwrap();
+
+        let previous = room.watch_party.take();
+        // Videos that were already loaded stay loaded, new ones are loaded again once the start is close
+        let preloaded = message.videos.is_none() && previous.as_ref().is_some_and(|party| party.preloaded);
+
+        let entries = match message.videos {
+            Some(videos) => videos.into_iter()
+                .map(|video| HistoryEntry {
+                    id: Uuid::new_v4(),
+                    url: video.url,
+                    video_id: video.video_id,
+                    metadata: *video.metadata,
+                    added_by: room.user_ref(message.user_id),
+                    added_at: now_millis(),
+                    pinned: false
+                })
+                .collect(),
+            None => previous.map(|party| party.entries).unwrap_or_default(),
+        };
+
+        room.watch_party = Some(WatchParty { start_at: message.start_at, entries, preloaded });
+
+        if let Some(countdown) = self.countdown(&message.room_id) {
+            self.broadcast(&message.room_id, &countdown, None).await;
+        }
+
+        self.save_watch_parties().await;
+
+        Ok(())
+    }
+}
+
+impl Handler<StateCanScheduleMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateCanScheduleMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        self.check_can_schedule(&message.room_id, message.user_id, message.videos)
+    }
+}
+
+impl Handler<StateCancelWatchPartyMessage> for JvsState {
+    type Return = Result<()>;
+
+    async fn handle(
+        &mut self,
+        message: StateCancelWatchPartyMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Result<()> {
+        let room = self.rooms.get_mut(&message.room_id).ok_or(anyhow!("Room not found"))?;
+
+        if room.users.get(&message.user_id).map(|user| user.role) != Some(Role::Host) {
+            return Err(anyhow!("Only the host can cancel the watch party"));
+        }
+
+        if room.watch_party.take().is_none() {
+            return Err(anyhow!("No watch party is scheduled"));
+        }
+
+        self.broadcast(&message.room_id, &ServerMsg::WatchPartyCancelled, None).await;
+        self.save_watch_parties().await;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures_util::StreamExt;
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 use tokio::time;
 use tokio_tungstenite::tungstenite::Message;
 use xtra::WeakAddress;
@@ -10,18 +11,28 @@ use std::time::Duration;
 use tokio_tungstenite::accept_async;
 use uuid::Uuid;
 
+use crate::data_types::connection_types::{Connection, StatePingMessage, StatePongMessage, StateSetClientOptionsMessage};
+use crate::data_types::buffering_types::StateSetBufferingMessage;
+use crate::data_types::directory_types::{StateListRoomsMessage, StateSetRoomPublicMessage, DEFAULT_PAGE_SIZE};
 use crate::data_types::instances_types::{InstancesManager, InstancesFetchVideoMessage};
-use crate::data_types::msg_types::{ClientMsg, ServerMsg};
-use crate::data_types::state_types::{JvsState, StateGenericMessage, StateGetCurrentVideoMessage, StateGetHistoryMessage, StateGetRoomShouldAnnounceRewind, StateRemoveUserMessage, StateSetReadyMessage};
-use crate::utils::{broadcast_message, send_connected_clients};
-
-use url::Url;
+use crate::data_types::lobby_types::{StateAnswerKnockMessage, StateSetRoomAccessMessage};
+use crate::data_types::moderation_types::{StateModerateMessage, StateSetModeratorMessage};
+use crate::data_types::msg_types::{ClientEnvelope, ClientMsg, ServerMsg};
+use crate::data_types::playback_types::{PendingOutcome, PlaybackCommand, StatePlaybackMessage, StatePlaybackReportMessage};
+use crate::data_types::presence_types::StateSetStatusMessage;
+use crate::data_types::queue_mode_types::{StateSetRepeatModeMessage, StateSetShuffleMessage};
+use crate::data_types::ready_types::StateStartNowMessage;
+use crate::data_types::settings_types::{RestrictedContent, StateGetRoomSettingsMessage, StateUpdateRoomSettingsMessage};
+use crate::data_types::state_types::{JvsState, StateCanControlMessage, StateGenericMessage, StateGetCurrentVideoMessage, StateRemoveUserMessage, StateSetReadyMessage, StateTickMessage, StateVideoEndedMessage, VideoMetadata};
+use crate::data_types::watch_party_types::{ScheduledVideo, StateCanScheduleMessage, StateCancelWatchPartyMessage, StateScheduleWatchPartyMessage};
+use crate::utils::{now_millis, parse_video_id};
 
 pub async fn handle_connection(
     state_addr: WeakAddress<JvsState>,
     instances_addr: WeakAddress<InstancesManager>,
     stream: TcpStream,
     peer: SocketAddr,
+    ping_interval: Duration,
 ) -> Result<()> {
     let ws_stream = accept_async(stream).await.expect("Failed to accept");
     println!("New WebSocket connection: {}", peer);
@@ -30,50 +41,55 @@ pub async fn handle_connection(
 
     let (ws_sender, mut ws_receiver) = ws_stream.split();
 
-    let mut interval_ping = time::interval(Duration::from_secs(20));
+    let mut interval_ping = time::interval(ping_interval);
 
     interval_ping.tick().await;
 
     // Add new user to Room on connection
     let user_id = Uuid::new_v4();
 
-    state_addr.send(StateGenericMessage::InsertUser { user_id, ws: ws_sender }).await?;
+    // Fires (or is dropped) once the state removes the connection, e.g. when the user is kicked
+    let (closed_sender, mut closed_receiver) = oneshot::channel();
+    let connection = Connection::new(ws_sender, peer.ip(), closed_sender);
+
+    state_addr.send(StateGenericMessage::InsertUser { user_id, connection }).await??;
+
+    // Whether the client sent anything (including protocol pongs) since the last ping
+    let mut active = true;
 
     // Handle incoming WebSocket messages
     loop {
         tokio::select! {
             val = ws_receiver.next() => {
-                match val.unwrap() {
-                    Ok(msg) => {
+                match val {
+                    Some(Ok(msg)) => {
+                        active = true;
+
                         if msg.is_text() {
                             if let Message::Text(msg) = msg {
                                 let _ = handle_msg(&msg, state_addr.clone(), instances_addr.clone(), user_id).await;
-                                state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: ServerMsg::UnlockSetVideo }).await?;
                             }
                         } else if msg.is_close() {
-                            let room_id = state_addr.send(StateRemoveUserMessage { user_id }).await?;
-
-                            if let Some (room_id) = room_id {
-                                send_connected_clients(state_addr.clone(), room_id).await?;
-                            }
+                            state_addr.send(StateRemoveUserMessage { user_id }).await?;
 
                             break;
                         }
                     }
-                    Err(_) => {
-                        // Remove the client of the room when a error occurs
-                        let room_id = state_addr.send(StateRemoveUserMessage { user_id }).await?;
-
-                        if let Some (room_id) = room_id {
-                            send_connected_clients(state_addr.clone(), room_id).await?;
-                        }
+                    _ => {
+                        // Remove the client of the room when a error occurs or the stream ends
+                        state_addr.send(StateRemoveUserMessage { user_id }).await?;
 
                         break;
                     },
                 }
             },
             _val = interval_ping.tick() => {
-                state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: ServerMsg::Ping }).await?;
+                // The state drops the connection after too many silent pings, which fires closed_receiver
+                state_addr.send(StatePingMessage { user_id, active }).await?;
+                active = false;
+            },
+            _val = &mut closed_receiver => {
+                break;
             },
         }
     }
@@ -81,138 +97,295 @@ pub async fn handle_connection(
     Ok(())
 }
 
+// Drives the state timeouts until the state stops
+pub async fn run_ticker(state_addr: WeakAddress<JvsState>, interval: Duration) {
+    let mut interval = time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if state_addr.send(StateTickMessage).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Handles a client message, acknowledging it once done when the client attached a request id
 async fn handle_msg(
     msg: &str,
     state_addr: WeakAddress<JvsState>,
     instances_addr: WeakAddress<InstancesManager>,
     user_id: Uuid,
 ) -> Result<()> {
-    let client_msg = serde_json::from_str::<ClientMsg>(msg);
-
-    if client_msg.is_err() {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(msg) else {
         return Ok(());
-    }
+    };
 
-    let client_msg = client_msg.unwrap();
+    // Read before parsing the message, so that invalid messages can be acknowledged too
+    let request_id = value.get("requestId").and_then(|request_id| request_id.as_str()).map(str::to_string);
 
-    match client_msg {
-        ClientMsg::SetName { name, room_id } => {
-            let result = state_addr.send(StateGenericMessage::RenameUser { user_id, name, room_id: room_id.clone() }).await;
+    let result = match serde_json::from_value::<ClientEnvelope>(value) {
+        Ok(envelope) => dispatch_msg(envelope, state_addr.clone(), instances_addr, user_id).await,
+        Err(error) => Err(anyhow!("Invalid message: {}", error)),
+    };
 
-            match result {
-                Ok(_) => send_connected_clients(state_addr, room_id).await?,
-                Err(_) => println!("User not found!")
-            }
-        }
-        ClientMsg::SetReady { room_id } => {
-            let should_play = state_addr.send(StateSetReadyMessage { room_id: room_id.clone() }).await?;
+    let Some(request_id) = request_id else {
+        return result.map(|_| ());
+    };
 
-            if should_play {
-                let set_playing = ServerMsg::SetPlaying {
-                    status: true
-                };
+    match result {
+        // Held back for coalescing, acknowledged once applied without holding up the next messages
+        Ok(Some(outcome)) => {
+            tokio::spawn(async move {
+                let result = outcome.await.unwrap_or_else(|_| Err(anyhow!("The command was dropped before it was applied")));
+                let _ = send_ack(&state_addr, user_id, request_id, &result).await;
+            });
 
-                broadcast_message(set_playing, state_addr, room_id).await?;
-            }
+            Ok(())
         },
-        ClientMsg::SendToRoom { room_id } => {
-            state_addr.send(StateGenericMessage::JoinRoom { room_id: room_id.clone(), user_id }).await?;
+        result => {
+            let result = result.map(|_| ());
+            send_ack(&state_addr, user_id, request_id, &result).await?;
 
-            let room_history = state_addr.send(StateGetHistoryMessage { room_id: room_id.clone() }).await?;
-            let history = ServerMsg::UpdateHistory { history: room_history.clone() };
-            broadcast_message(history, state_addr.clone(), room_id.clone()).await?;
+            result
+        },
+    }
+}
 
-            send_connected_clients(state_addr.clone(), room_id.clone()).await?;
+async fn send_ack(state_addr: &WeakAddress<JvsState>, user_id: Uuid, request_id: String, result: &Result<()>) -> Result<()> {
+    let ack = ServerMsg::Ack {
+        request_id,
+        ok: result.is_ok(),
+        error: result.as_ref().err().map(|error| error.to_string())
+    };
 
-            // Send the last video in room to the client when he enter the room
-            if room_history.len() > 0 {
-                let last_video = room_history.last().unwrap().clone();
-                let payload = ServerMsg::SetVideo { video_id: last_video.video_id.clone(), is_restricted_video: false };
+    state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: ack }).await?
+}
 
-                state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: payload }).await?;
-            }
-        },
-        ClientMsg::SetVideo { url, room_id } => {
-            let parsed_url = Url::parse(
-                &url
-            )?;
+async fn dispatch_msg(
+    envelope: ClientEnvelope,
+    state_addr: WeakAddress<JvsState>,
+    instances_addr: WeakAddress<InstancesManager>,
+    user_id: Uuid,
+) -> Result<Option<PendingOutcome>> {
+    let ClientEnvelope { last_seq, msg: client_msg } = envelope;
+    let received_at = now_millis();
 
-            if !["www.youtube.com", "youtube.com", "youtu.be"].contains(&parsed_url.host_str().unwrap_or("google.com")) {
-                return Ok(());
-            }
+    // Muted users cannot control the playback
+    if let Some(room_id) = client_msg.controlled_room() {
+        let can_control = state_addr.send(StateCanControlMessage { room_id: room_id.to_string(), user_id }).await?;
 
-            let video_id = match parsed_url.host_str().unwrap() {
-                "youtu.be" => parsed_url.path()[1..].to_string(),
-                "youtube.com" | "www.youtube.com" => {
-                    if !parsed_url.path().starts_with("/shorts/") {
-                        parsed_url.query_pairs().find(|p| p.0 == "v").unwrap_or(("".into(), "".into())).1.to_string()
-                    } else {
-                        parsed_url.path()[8..].to_string()
-                    }
-                },
-                _ => String::default()
-            };
+        if !can_control {
+            return Err(anyhow!("You cannot control the playback in this room"));
+        }
+    }
 
-            if video_id == "" {
-                return Ok(());
-            }
+    match client_msg {
+        ClientMsg::SetName { name, room_id } => {
+            state_addr.send(StateGenericMessage::RenameUser { user_id, name, room_id }).await??;
+        },
+        ClientMsg::SetReady { room_id } => {
+            // Starts the video once everyone is ready
+            state_addr.send(StateSetReadyMessage { room_id, user_id }).await?;
+        },
+        ClientMsg::StartNow { room_id } => {
+            state_addr.send(StateStartNowMessage { room_id, user_id }).await??;
+        },
+        ClientMsg::SendToRoom { room_id, resume_token } => {
+            // Joining also sends the snapshots and the current video to the new user
+            state_addr.send(StateGenericMessage::JoinRoom { room_id, user_id, resume_token }).await??;
+        },
+        ClientMsg::SetVideo { url, room_id } => {
+            let video_id = parse_video_id(&url).ok_or(anyhow!("Invalid video url"))?;
 
-            let room_current_video = state_addr.send(StateGetCurrentVideoMessage { room_id: room_id.clone() }).await?;
+            let room_current_video = state_addr.send(StateGetCurrentVideoMessage { room_id: room_id.clone() }).await?
+                .ok_or(anyhow!("Room not found"))?;
 
             if room_current_video == video_id {
-                return Ok(());
+                return Ok(None);
             }
 
-            let basic_info = instances_addr.send(InstancesFetchVideoMessage { video_id: video_id.clone() }).await??;
+            let metadata = fetch_video_metadata(&video_id, instances_addr).await?.ok_or(anyhow!("Video not found"))?;
+            check_restricted_content(&state_addr, &room_id, &metadata).await?;
 
-            match basic_info.items.first() {
-                Some(item) => {
-                    state_addr.send(StateGenericMessage::SetVideo {
-                        room_id: room_id.clone(), video_id: video_id.clone(), url, title: item.snippet.title.clone()
-                    }).await?;
+            state_addr.send(StateGenericMessage::SetVideo {
+                room_id, video_id, url, metadata, added_by: user_id
+            }).await??;
+        },
+        ClientMsg::QueueVideo { url, room_id } => {
+            let video_id = parse_video_id(&url).ok_or(anyhow!("Invalid video url"))?;
+            let metadata = fetch_video_metadata(&video_id, instances_addr).await?.ok_or(anyhow!("Video not found"))?;
+            check_restricted_content(&state_addr, &room_id, &metadata).await?;
+
+            state_addr.send(StateGenericMessage::QueueVideo {
+                room_id, video_id, url, metadata, added_by: user_id
+            }).await??;
+        },
+        ClientMsg::RemoveQueuedVideo { entry_id, room_id } => {
+            state_addr.send(StateGenericMessage::RemoveQueuedVideo { room_id, entry_id }).await??;
+        },
+        ClientMsg::VoteSkip { room_id } => {
+            state_addr.send(StateGenericMessage::SkipVote { room_id, user_id, vote: true }).await??;
+        },
+        ClientMsg::RetractVote { room_id } => {
+            state_addr.send(StateGenericMessage::SkipVote { room_id, user_id, vote: false }).await??;
+        },
+        ClientMsg::SetPlaying { status, time, room_id } => {
+            let command = PlaybackCommand::SetPlaying { status, time };
+            return state_addr.send(StatePlaybackMessage { room_id, user_id, command, last_seq }).await?;
+        },
+        ClientMsg::Seeked { time, room_id } => {
+            let command = PlaybackCommand::Seek { time };
+            return state_addr.send(StatePlaybackMessage { room_id, user_id, command, last_seq }).await?;
+        },
+        ClientMsg::SetPlaybackRate { rate, room_id } => {
+            let command = PlaybackCommand::SetRate { rate };
+            return state_addr.send(StatePlaybackMessage { room_id, user_id, command, last_seq }).await?;
+        },
+        ClientMsg::Rewind { seconds, room_id } => {
+            let command = PlaybackCommand::Rewind { seconds };
+            return state_addr.send(StatePlaybackMessage { room_id, user_id, command, last_seq }).await?;
+        },
+        ClientMsg::ReplayHistoryEntry { entry_id, room_id } => {
+            state_addr.send(StateGenericMessage::ReplayHistoryEntry { room_id, entry_id, user_id }).await??;
+        },
+        ClientMsg::RemoveHistoryEntry { entry_id, room_id } => {
+            state_addr.send(StateGenericMessage::RemoveHistoryEntry { room_id, entry_id }).await??;
+        },
+        ClientMsg::ClearHistory { room_id } => {
+            state_addr.send(StateGenericMessage::ClearHistory { room_id }).await??;
+        },
+        ClientMsg::PinHistoryEntry { entry_id, pinned, room_id } => {
+            state_addr.send(StateGenericMessage::PinHistoryEntry { room_id, entry_id, pinned }).await??;
+        },
+        ClientMsg::SendChat { message, room_id } => {
+            state_addr.send(StateGenericMessage::SendChat { user_id, room_id, message }).await??;
+        },
+        ClientMsg::Moderate { user_id: target_id, action, reason, room_id } => {
+            state_addr.send(StateModerateMessage { room_id, moderator_id: user_id, target_id, action, reason }).await??;
+        },
+        ClientMsg::SetModerator { user_id: target_id, moderator, room_id } => {
+            state_addr.send(StateSetModeratorMessage { room_id, host_id: user_id, target_id, moderator }).await??;
+        },
+        ClientMsg::SetStatus { status, room_id } => {
+            state_addr.send(StateSetStatusMessage { room_id, user_id, status }).await?;
+        },
+        ClientMsg::SetRoomPublic { public, room_id } => {
+            state_addr.send(StateSetRoomPublicMessage { room_id, user_id, public }).await??;
+        },
+        ClientMsg::SetRoomAccess { max_users, require_approval, room_id } => {
+            state_addr.send(StateSetRoomAccessMessage { room_id, user_id, max_users, require_approval }).await??;
+        },
+        ClientMsg::AnswerKnock { user_id: target_id, approve, room_id } => {
+            state_addr.send(StateAnswerKnockMessage { room_id, user_id, target_id, approve }).await??;
+        },
+        ClientMsg::ListRooms { query, page, page_size } => {
+            let page = state_addr.send(StateListRoomsMessage {
+                query,
+                page: page.unwrap_or(1),
+                page_size: page_size.unwrap_or(DEFAULT_PAGE_SIZE)
+            }).await?;
+
+            state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: ServerMsg::RoomList { page } }).await??;
+        },
+        ClientMsg::PlaybackReport { position, playing, buffering, rate, room_id } => {
+            state_addr.send(StatePlaybackReportMessage { room_id, user_id, position, playing, buffering, rate }).await??;
+        },
+        ClientMsg::GetRoomSettings { room_id } => {
+            let settings = state_addr.send(StateGetRoomSettingsMessage { room_id }).await?.ok_or(anyhow!("Room not found"))?;
+            state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: ServerMsg::RoomSettings { settings } }).await??;
+        },
+        ClientMsg::UpdateRoomSettings { settings, room_id } => {
+            state_addr.send(StateUpdateRoomSettingsMessage { room_id, user_id, update: settings }).await??;
+        },
+        ClientMsg::VideoEnded { room_id } => {
+            state_addr.send(StateVideoEndedMessage { room_id, user_id }).await?;
+        },
+        ClientMsg::ScheduleWatchParty { start_at, urls, room_id } => {
+            let count = urls.as_ref().map(Vec::len);
+            state_addr.send(StateCanScheduleMessage { room_id: room_id.clone(), user_id, videos: count }).await??;
 
-                    let room_history = state_addr.send(StateGetHistoryMessage { room_id: room_id.clone() }).await?;
+            let videos = match urls {
+                Some(urls) => {
+                    let mut videos = Vec::with_capacity(urls.len());
 
-                    // If ytRating is not present, the video dont have age restriction
-                    if let None = item.content_details.content_rating.yt_rating {
-                        let payload = ServerMsg::SetVideo { video_id, is_restricted_video: false };
-                        broadcast_message(payload, state_addr.clone(), room_id.clone()).await?;
+                    for url in urls {
+                        let video_id = parse_video_id(&url).ok_or(anyhow!("Invalid video url"))?;
+                        let metadata = fetch_video_metadata(&video_id, instances_addr.clone()).await?.ok_or(anyhow!("Video not found"))?;
+                        check_restricted_content(&state_addr, &room_id, &metadata).await?;
 
-                        let history = ServerMsg::UpdateHistory { history: room_history };
-                        broadcast_message(history, state_addr.clone(), room_id.clone()).await?;
+                        videos.push(ScheduledVideo { video_id, url, metadata });
                     }
+
+                    Some(videos)
                 },
-                None => {
-                    println!("No item found");
-                },
-            }
-        },
-        ClientMsg::SetPlaying { status, room_id } => {
-            let set_playing = ServerMsg::SetPlaying {
-                status
+                None => None,
             };
 
-            broadcast_message(set_playing, state_addr, room_id).await?;
+            state_addr.send(StateScheduleWatchPartyMessage { room_id, user_id, start_at, videos }).await??;
         },
-        ClientMsg::Seeked { time, room_id } => {
-            let seek = ServerMsg::Seeked { time };
-
-            broadcast_message(seek, state_addr, room_id).await?;
+        ClientMsg::CancelWatchParty { room_id } => {
+            state_addr.send(StateCancelWatchPartyMessage { room_id, user_id }).await??;
         },
-        ClientMsg::SetPlaybackRate { rate, room_id } => {
-            let rate = ServerMsg::SetPlaybackRate { rate };
-            broadcast_message(rate, state_addr, room_id).await?;
+        ClientMsg::SetRepeatMode { mode, room_id } => {
+            state_addr.send(StateSetRepeatModeMessage { room_id, mode }).await?;
         },
-        ClientMsg::Rewind { seconds, room_id } => {
-            let should_announce = state_addr.send(StateGetRoomShouldAnnounceRewind{ room_id: room_id.clone() }).await?;
-            let rewind = ServerMsg::Rewind { seconds, should_announce };
-
-            broadcast_message(rewind, state_addr, room_id).await?;
+        ClientMsg::SetShuffle { enabled, seed, room_id } => {
+            state_addr.send(StateSetShuffleMessage { room_id, enabled, seed }).await?;
+        },
+        ClientMsg::RequestSnapshot { room_id } => {
+            state_addr.send(StateGenericMessage::SendSnapshot { user_id, room_id }).await??;
+        },
+        ClientMsg::Buffering { room_id } => {
+            state_addr.send(StateSetBufferingMessage { room_id, user_id, buffering: true }).await?;
+        },
+        ClientMsg::BufferingEnded { room_id } => {
+            state_addr.send(StateSetBufferingMessage { room_id, user_id, buffering: false }).await?;
+        },
+        ClientMsg::SetClientOptions { echo_own_actions, system_notices } => {
+            state_addr.send(StateSetClientOptionsMessage { user_id, echo_own_actions, system_notices }).await?;
+        },
+        ClientMsg::TimeSync { client_time } => {
+            let time_sync = ServerMsg::TimeSync { client_time, server_receive: received_at, server_transmit: now_millis() };
+            state_addr.send(StateGenericMessage::SendMsgToUser { user_id, message: time_sync }).await??;
         },
-        ClientMsg::Pong => {
-            println!("Client is alive");
+        ClientMsg::Pong { nonce } => {
+            if let Some(nonce) = nonce {
+                state_addr.send(StatePongMessage { user_id, nonce }).await?;
+            }
         }
     }
 
+    Ok(None)
+}
+
+// Rejects age restricted videos in rooms that block them
+async fn check_restricted_content(
+    state_addr: &WeakAddress<JvsState>,
+    room_id: &str,
+    metadata: &VideoMetadata,
+) -> Result<()> {
+    let settings = state_addr.send(StateGetRoomSettingsMessage { room_id: room_id.to_string() }).await?.ok_or(anyhow!("Room not found"))?;
+
+    if metadata.age_restricted && settings.restricted_content == RestrictedContent::Block {
+        return Err(anyhow!("Age restricted videos are blocked in this room"));
+    }
+
     Ok(())
 }
+
+// Looks up the video on the Youtube Data API. Returns None for unknown videos
+async fn fetch_video_metadata(
+    video_id: &str,
+    instances_addr: WeakAddress<InstancesManager>,
+) -> Result<Option<Box<VideoMetadata>>> {
+    let basic_info = instances_addr.send(InstancesFetchVideoMessage { video_id: video_id.to_string() }).await??;
+
+    match basic_info.items.first() {
+        Some(item) => Ok(Some(Box::new(VideoMetadata::from(item)))),
+        None => {
+            println!("No item found");
+            Ok(None)
+        },
+    }
+}
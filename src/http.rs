@@ -0,0 +1,81 @@
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Url;
+use xtra::WeakAddress;
+
+use crate::data_types::directory_types::{StateListRoomsMessage, DEFAULT_PAGE_SIZE};
+use crate::data_types::metrics_types::StateGetMetricsMessage;
+use crate::data_types::state_types::JvsState;
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// Clients that do not send their request in time are dropped, so idle sockets do not pile up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Minimal HTTP/1.1 handler for the read-only api. Every response closes the connection
+pub async fn handle_http_connection(state_addr: WeakAddress<JvsState>, mut stream: TcpStream) -> Result<()> {
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream)).await
+        .map_err(|_| anyhow!("Request timed out"))??;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+
+    let url = Url::parse(&format!("http://localhost{}", target))?;
+
+    let (status, body) = match (method, url.path()) {
+        ("GET", "/rooms") => {
+            let query_param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.to_string());
+
+            let page = state_addr.send(StateListRoomsMessage {
+                query: query_param("q"),
+                page: query_param("page").and_then(|page| page.parse().ok()).unwrap_or(1),
+                page_size: query_param("pageSize").and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_PAGE_SIZE)
+            }).await?;
+
+            ("200 OK", to_json(&page))
+        },
+        ("GET", "/metrics") => ("200 OK", to_json(&state_addr.send(StateGetMetricsMessage).await?)),
+        (_, "/rooms" | "/metrics") => ("405 Method Not Allowed", error_json("Method not allowed")),
+        _ => ("404 Not Found", error_json("Not found")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn read_request_line(stream: &mut TcpStream) -> Result<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    // Only the request line matters, but read the whole head so the client isn't reset mid request
+    while !buffer.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+
+        if read == 0 || buffer.len() + read > MAX_REQUEST_SIZE {
+            return Err(anyhow!("Invalid request"));
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&buffer);
+
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("Failed to serialize")
+}
+
+fn error_json(error: &str) -> String {
+    to_json(&serde_json::json!({ "error": error }))
+}
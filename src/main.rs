@@ -1,6 +1,10 @@
 use anyhow::Result;
+use data_types::config_types::ServerConfig;
 use data_types::state_types::JvsState;
-use handlers::handle_connection;
+use dotenv::dotenv;
+use handlers::{handle_connection, run_ticker};
+use http::handle_http_connection;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use xtra::Mailbox;
 
@@ -8,12 +12,25 @@ use crate::data_types::instances_types::InstancesManager;
 
 mod data_types;
 mod handlers;
+mod http;
+mod names;
 mod utils;
 
+// How often the state checks its timeouts
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let state_addr = xtra::spawn_tokio(JvsState::default(), Mailbox::unbounded());
-    let instances_addr = xtra::spawn_tokio(InstancesManager::default(), Mailbox::unbounded());
+    dotenv().ok();
+
+    let config = ServerConfig::from_env();
+    let ping_interval = Duration::from_secs(config.ping_interval_secs);
+    let http_server = TcpListener::bind(&config.http_address).await.expect("Http server bind failed");
+
+    let state_addr = xtra::spawn_tokio(JvsState::new(config), Mailbox::unbounded());
+    let instances_addr = xtra::spawn_tokio(InstancesManager, Mailbox::unbounded());
+    tokio::spawn(run_ticker(state_addr.downgrade(), TICK_INTERVAL));
+
     let server = TcpListener::bind("127.0.0.1:9001").await.expect("Server bind failed");
 
     loop {
@@ -24,7 +41,10 @@ async fn main() -> Result<()> {
                     .expect("connected streams should have a peer address");
                 println!("Peer address: {}", peer);
 
-                tokio::spawn(handle_connection(state_addr.downgrade(), instances_addr.downgrade(), stream, peer));
+                tokio::spawn(handle_connection(state_addr.downgrade(), instances_addr.downgrade(), stream, peer, ping_interval));
+            },
+            Ok((stream, _)) = http_server.accept() => {
+                tokio::spawn(handle_http_connection(state_addr.downgrade(), stream));
             },
         }
     }
@@ -0,0 +1,146 @@
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+pub const MAX_NAME_LENGTH: usize = 32;
+
+// Names that could be mistaken for the server or the room staff
+const RESERVED_NAMES: [&str; 7] = ["admin", "host", "moderator", "mod", "server", "system", "jvs together"];
+
+const ADJECTIVES: [&str; 24] = [
+    "Curious", "Sleepy", "Brave", "Clever", "Gentle", "Happy", "Jolly", "Lucky",
+    "Mighty", "Nimble", "Quiet", "Witty", "Cosmic", "Fuzzy", "Swift", "Sunny",
+    "Bouncy", "Cheerful", "Dapper", "Fearless", "Groovy", "Humble", "Mellow", "Zesty",
+];
+
+const ANIMALS: [&str; 24] = [
+    "Otter", "Panda", "Fox", "Koala", "Penguin", "Owl", "Badger", "Falcon",
+    "Lynx", "Hedgehog", "Dolphin", "Raccoon", "Tiger", "Walrus", "Llama", "Gecko",
+    "Beaver", "Wombat", "Moose", "Heron", "Squirrel", "Turtle", "Yak", "Zebra",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameError {
+    Empty,
+    TooLong,
+    InvalidCharacters,
+    Reserved,
+    Blocked,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "Name cannot be empty"),
+            NameError::TooLong => write!(f, "Name cannot be longer than {} characters", MAX_NAME_LENGTH),
+            NameError::InvalidCharacters => write!(f, "Name contains invalid characters"),
+            NameError::Reserved => write!(f, "Name is reserved"),
+            NameError::Blocked => write!(f, "Name contains a blocked word"),
+        }
+    }
+}
+
+// Normalizes (NFKC, collapsed whitespace) and validates a user provided name
+pub fn normalize_name(name: &str, blocked_words: &[String]) -> Result<String, NameError> {
+    let normalized: String = name.nfkc().collect();
+
+    if normalized.chars().any(|c| c.is_control() || is_invisible(c)) {
+        return Err(NameError::InvalidCharacters);
+    }
+
+    let name = normalized.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong);
+    }
+
+    let lowercase = name.to_lowercase();
+
+    if RESERVED_NAMES.contains(&lowercase.as_str()) {
+        return Err(NameError::Reserved);
+    }
+
+    if blocked_words.iter().any(|word| lowercase.contains(&word.to_lowercase())) {
+        return Err(NameError::Blocked);
+    }
+
+    Ok(name)
+}
+
+// Appends a numeric suffix ("Ana 2") when the name is already taken, ignoring case
+pub fn unique_name<'a>(name: &str, taken: impl Iterator<Item = &'a str> + Clone) -> String {
+    let is_taken = |candidate: &str| taken.clone().any(|other| other.to_lowercase() == candidate.to_lowercase());
+
+    if !is_taken(name) {
+        return name.to_string();
+    }
+
+    (2..)
+        .map(|suffix| {
+            let suffix = format!(" {}", suffix);
+            let base: String = name.chars().take(MAX_NAME_LENGTH - suffix.chars().count()).collect();
+
+            format!("{}{}", base.trim_end(), suffix)
+        })
+        .find(|candidate| !is_taken(candidate))
+        .unwrap()
+}
+
+// Readable default name such as "Curious Otter"
+pub fn random_name() -> String {
+    let random = Uuid::new_v4().as_u128();
+    let adjective = ADJECTIVES[(random % ADJECTIVES.len() as u128) as usize];
+    let animal = ANIMALS[((random >> 64) % ANIMALS.len() as u128) as usize];
+
+    format!("{} {}", adjective, animal)
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_names() {
+        // Fullwidth letters fold to their ASCII form
+        assert_eq!(normalize_name("Ａｎａ", &[]), Ok("Ana".to_string()));
+        assert_eq!(normalize_name("  Ana   Maria ", &[]), Ok("Ana Maria".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert_eq!(normalize_name("   ", &[]), Err(NameError::Empty));
+        assert_eq!(normalize_name(&"a".repeat(MAX_NAME_LENGTH + 1), &[]), Err(NameError::TooLong));
+        assert_eq!(normalize_name("An\u{200B}a", &[]), Err(NameError::InvalidCharacters));
+        assert_eq!(normalize_name("Ana\n", &[]), Err(NameError::InvalidCharacters));
+        assert_eq!(normalize_name("ADMIN", &[]), Err(NameError::Reserved));
+        // The folded form is checked, so lookalikes are reserved too
+        assert_eq!(normalize_name("Ｈｏｓｔ", &[]), Err(NameError::Reserved));
+        assert_eq!(normalize_name("Big Spammer", &["spam".to_string()]), Err(NameError::Blocked));
+    }
+
+    #[test]
+    fn deduplicates_names() {
+        let taken = ["Ana", "ana 2", "Bob"];
+
+        assert_eq!(unique_name("Carl", taken.iter().copied()), "Carl");
+        assert_eq!(unique_name("ANA", taken.iter().copied()), "ANA 3");
+    }
+
+    #[test]
+    fn truncates_names_to_fit_the_suffix() {
+        let name = "a".repeat(MAX_NAME_LENGTH);
+        let taken = [name.as_str()];
+        let unique = unique_name(&name, taken.iter().copied());
+
+        assert_eq!(unique.chars().count(), MAX_NAME_LENGTH);
+        assert_eq!(unique, format!("{} 2", "a".repeat(MAX_NAME_LENGTH - 2)));
+    }
+}
@@ -1,22 +1,176 @@
-use anyhow::{anyhow, Result};
-use tokio_tungstenite::tungstenite::Message;
-use xtra::WeakAddress;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
 
-use crate::data_types::state_types::{JvsState, StateGetClientsMessage, StateGenericMessage};
-use crate::data_types::msg_types::ServerMsg;
+// Extracts the video id from youtube.com, youtu.be and shorts urls
+pub fn parse_video_id(url: &str) -> Option<String> {
+    let parsed_url = Url::parse(url).ok()?;
 
-pub async fn broadcast_message(msg: ServerMsg, addr: WeakAddress<JvsState>, room_id: String) -> Result<()> {
-    let server_msg = serde_json::to_string(&msg).expect("Failed to serialize");
+    let video_id = match parsed_url.host_str()? {
+        "youtu.be" => parsed_url.path()[1..].to_string(),
+        "youtube.com" | "www.youtube.com" => {
+            if !parsed_url.path().starts_with("/shorts/") {
+                parsed_url.query_pairs().find(|p| p.0 == "v").unwrap_or(("".into(), "".into())).1.to_string()
+            } else {
+                parsed_url.path()[8..].to_string()
+            }
+        },
+        _ => String::default()
+    };
+
+    if video_id.is_empty() {
+        return None;
+    }
 
-    return addr.send(StateGenericMessage::SendSocketMessage { room_id, message: Message::Text(server_msg.clone()) }).await.map_err(|e| anyhow!(e));
+    Some(video_id)
 }
 
-pub async fn send_connected_clients(addr: WeakAddress<JvsState>, room_id: String) -> Result<()> {
-    let connected_clients = ServerMsg::ConnectedClients {
-        clients: addr.send(StateGetClientsMessage { room_id: room_id.clone() }).await?
-    };
+// Parses the ISO-8601 durations returned by the Youtube Data API (e.g. "PT1H2M3S", "P1DT2H") into seconds
+pub fn parse_iso8601_duration(duration: &str) -> Option<u64> {
+    let rest = duration.strip_prefix('P')?;
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    let mut in_time = false;
+
+    for c in rest.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' => number.push(c),
+            unit => {
+                let value: f64 = number.parse().ok()?;
+                number.clear();
+
+                let multiplier = match (unit, in_time) {
+                    ('W', false) => 7 * 24 * 3600,
+                    ('D', false) => 24 * 3600,
+                    ('H', true) => 3600,
+                    ('M', true) => 60,
+                    ('S', true) => 1,
+                    _ => return None,
+                };
 
-    broadcast_message(connected_clients, addr, room_id).await?;
+                let component = value * multiplier as f64;
+
+                if component >= u64::MAX as f64 {
+                    return None;
+                }
+
+                seconds = seconds.checked_add(component as u64)?;
+            }
+        }
+    }
+
+    if !number.is_empty() {
+        return None;
+    }
+
+    Some(seconds)
+}
+
+// Formats a video position as "m:ss", or "h:mm:ss" past the hour
+pub fn format_timestamp(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+// SplitMix64 step, a tiny deterministic generator so anything derived from a seed can be reproduced
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+
+    z ^ (z >> 31)
+}
 
-    Ok(())
-}
\ No newline at end of file
+// Fisher-Yates shuffle driven by the seed, the same seed always gives the same order
+pub fn seeded_shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+
+    for i in (1..items.len()).rev() {
+        let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_iso8601_durations() {
+        assert_eq!(parse_iso8601_duration("PT1H2M3S"), Some(3723));
+        assert_eq!(parse_iso8601_duration("P1DT2H"), Some(93600));
+        assert_eq!(parse_iso8601_duration("P0D"), Some(0));
+        assert_eq!(parse_iso8601_duration("PT4M13S"), Some(253));
+        assert_eq!(parse_iso8601_duration("P1W"), Some(604800));
+    }
+
+    #[test]
+    fn rejects_malformed_iso8601_durations() {
+        assert_eq!(parse_iso8601_duration(""), None);
+        assert_eq!(parse_iso8601_duration("1H2M"), None);
+        // Hours and minutes only exist after the T
+        assert_eq!(parse_iso8601_duration("P1H"), None);
+        assert_eq!(parse_iso8601_duration("PT5"), None);
+        assert_eq!(parse_iso8601_duration("PTS"), None);
+        assert_eq!(parse_iso8601_duration("PT1X"), None);
+        assert_eq!(parse_iso8601_duration("PT99999999999999999999H1M"), None);
+        assert_eq!(parse_iso8601_duration("PT99999999999999999999S"), None);
+    }
+
+    #[test]
+    fn shuffles_the_same_way_for_the_same_seed() {
+        let mut first: Vec<u32> = (0..20).collect();
+        let mut second = first.clone();
+
+        seeded_shuffle(&mut first, 42);
+        seeded_shuffle(&mut second, 42);
+
+        assert_eq!(first, second);
+        assert_ne!(first, (0..20).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn shuffles_into_a_permutation() {
+        let mut items: Vec<u32> = (0..20).collect();
+
+        seeded_shuffle(&mut items, 7);
+        items.sort();
+
+        assert_eq!(items, (0..20).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn shuffles_differently_for_other_seeds() {
+        let mut first: Vec<u32> = (0..20).collect();
+        let mut second = first.clone();
+
+        seeded_shuffle(&mut first, 1);
+        seeded_shuffle(&mut second, 2);
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn shuffles_empty_and_single_item_slices() {
+        let mut empty: Vec<u32> = Vec::new();
+        let mut single = vec![1];
+
+        seeded_shuffle(&mut empty, 3);
+        seeded_shuffle(&mut single, 3);
+
+        assert!(empty.is_empty());
+        assert_eq!(single, vec![1]);
+    }
+}